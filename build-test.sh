@@ -32,6 +32,12 @@ do
 	done
 done
 
+# File names requiring escaping in shasum files, or not valid UTF-8
+for name in 'back\slash' $'new\nline' $'carriage\rreturn' $'latin1-\xe9'
+do
+	dd if=/dev/urandom of="$DIR/src/$name" count=1 bs=1025
+done
+
 mkdir -p -- "$DIR/dst/"
 find "$DIR/dst" -name "shasum.*.txt" -exec rm -- '{}' ';'
 
@@ -53,6 +59,164 @@ target/release/dirverify --silent --hash-file "$SHASUM" "$DIR/src" "$DIR/dst"
 # Verify --silent silences output
 target/release/dirverify --silent --hash-file "$SHASUM" "$DIR/src" "$DIR/dst" | IFS= read -r -n 1 firstbyte && exit 1
 
+# Names invalid on NTFS are renamed, and recorded beside the manifest
+mkdir -p -- "$DIR/dst-ntfs/"
+target/release/dircopy -i "$DIR/src" -o "$DIR/dst-ntfs" --target-profile ntfs --incompatible-names rename
+find "$DIR/dst-ntfs" -name "renames.*.txt" | grep -q .
+target/release/dirverify --silent "$DIR/dst-ntfs"
+
+# Tar output, checked after extraction
+rm -rf -- "$DIR/tar"
+mkdir -p -- "$DIR/tar/extract"
+target/release/dircopy -i "$DIR/src" -o "$DIR/tar/dst.tar" --output-format tar --tar-manifest-member
+tar -x --warning=no-unknown-keyword -f "$DIR/tar/dst.tar" -C "$DIR/tar/extract"
+cd -- "$DIR/tar/extract" && sha256sum -c -- shasum.*.txt
+cd -- "$CUR"
+target/release/dirverify --silent --tar "$DIR/tar/dst.tar"
+target/release/dirverify --silent --tar --hash-file "$SHASUM" "$DIR/tar/dst.tar"
+
+# Tar input, extracted and hashed in one pass
+rm -rf -- "$DIR/tar/unpack"
+mkdir -p -- "$DIR/tar/unpack"
+target/release/dircopy -i - -o "$DIR/tar/unpack" --input-format tar < "$DIR/tar/dst.tar"
+target/release/dirverify --silent "$DIR/tar/unpack"
+
+# BSD tagged shasum file, and a list mixing algorithms
+rm -rf -- "$DIR/dst-bsd"
+mkdir -p -- "$DIR/dst-bsd"
+target/release/dircopy -i "$DIR/src/subdir_a" -o "$DIR/dst-bsd" --manifest-format bsd
+target/release/dirverify --silent "$DIR/dst-bsd"
+cd -- "$DIR/src/subdir_a"
+{ sha1sum --tag subdir_b/1025; sha512sum --tag subdir_b/1026; sha256sum subdir_b/1024; } > "$DIR/mixed.txt"
+cd -- "$CUR"
+target/release/dirverify --silent --hash-file "$DIR/mixed.txt" "$DIR/dst-bsd"
+
+# Tree hash, equal for copies of the same tree
+rm -rf -- "$DIR/dst-tree1" "$DIR/dst-tree2"
+mkdir -p -- "$DIR/dst-tree1" "$DIR/dst-tree2"
+target/release/dircopy -i "$DIR/src" -o "$DIR/dst-tree1" --tree-hash
+target/release/dircopy -i "$DIR/src" -o "$DIR/dst-tree2" --tree-hash --manifest-format jsonl
+cmp -- "$DIR"/dst-tree1/shasum.*.tree "$DIR"/dst-tree2/shasum.*.tree
+target/release/dirverify --silent "$DIR/dst-tree1" "$DIR/dst-tree2"
+
+# Chunk hashes, locating a damaged range
+rm -rf -- "$DIR/dst-chunks"
+mkdir -p -- "$DIR/dst-chunks"
+target/release/dircopy -i "$DIR/src/subdir_c" -o "$DIR/dst-chunks" --chunk-size 4K
+target/release/dirverify --silent "$DIR/dst-chunks"
+printf x | dd of="$DIR/dst-chunks/1048576" bs=1 seek=5000 conv=notrunc
+target/release/dirverify "$DIR/dst-chunks" | grep -q -- "1048576: differing bytes 4096-8191$"
+
+# Extended shasum file, with size checks
+rm -rf -- "$DIR/dst-jsonl"
+mkdir -p -- "$DIR/dst-jsonl"
+target/release/dircopy -i "$DIR/src" -o "$DIR/dst-jsonl" --manifest-format jsonl
+target/release/dirverify --silent "$DIR/dst-jsonl"
+printf x >> "$DIR/dst-jsonl/subdir_c/1024"
+if target/release/dirverify --silent "$DIR/dst-jsonl"
+then
+	exit 1
+fi
+
+# Compressed copy, verified by decompressing
+rm -rf -- "$DIR/dst-zstd"
+mkdir -p -- "$DIR/dst-zstd"
+target/release/dircopy -i "$DIR/src" -o "$DIR/dst-zstd" --compress zstd --compressed-hashes
+target/release/dirverify --silent "$DIR/dst-zstd"
+target/release/dirverify --silent --hash-file "$SHASUM" "$DIR/dst-zstd"
+
+# Encrypted copy, verified by decrypting, and restored
+rm -rf -- "$DIR/dst-age" "$DIR/restore"
+mkdir -p -- "$DIR/dst-age" "$DIR/restore"
+# Test key only, not to be used for anything else
+echo "AGE-SECRET-KEY-1D5K3ZR4QTATNNR0SDKRC9TK0L9N5SS6487FPUD2YT3AWE87R5YWQCGMPS8" > "$DIR/key.txt"
+target/release/dircopy -i "$DIR/src/subdir_a" -o "$DIR/dst-age" --compress zstd --encryption age --recipient age1xezm4pfc6j2gsmkrx0qjy7ddru3tsnmksxf0gm09qpypuh4qdy5qzjvhn0
+target/release/dirverify --silent --key-file "$DIR/key.txt" "$DIR/dst-age"
+target/release/dircopy -i "$DIR/dst-age" -o "$DIR/restore" --restore --key-file "$DIR/key.txt"
+diff -r -x "shasum.*.txt" -- "$DIR/src/subdir_a" "$DIR/restore"
+
+# Passphrase encrypts the key of the copy once, reused by later runs
+rm -rf -- "$DIR/dst-age-passphrase" "$DIR/restore-passphrase"
+mkdir -p -- "$DIR/dst-age-passphrase" "$DIR/restore-passphrase"
+echo "test passphrase" > "$DIR/passphrase.txt"
+echo "other passphrase" > "$DIR/passphrase-other.txt"
+target/release/dircopy -i "$DIR/src/subdir_a" -o "$DIR/dst-age-passphrase" --encryption age --passphrase-file "$DIR/passphrase.txt"
+test -f "$DIR/dst-age-passphrase/dircopy.key"
+if target/release/dircopy -i "$DIR/src/subdir_a" -o "$DIR/dst-age-passphrase" --encryption age --passphrase-file "$DIR/passphrase-other.txt" --overwrite-policy always
+then
+	exit 1
+fi
+target/release/dircopy -i "$DIR/src/subdir_a" -o "$DIR/dst-age-passphrase" --encryption age --passphrase-file "$DIR/passphrase.txt" --overwrite-policy always
+target/release/dirverify --silent --passphrase-file "$DIR/passphrase.txt" "$DIR/dst-age-passphrase"
+target/release/dircopy -i "$DIR/dst-age-passphrase" -o "$DIR/restore-passphrase" --restore --passphrase-file "$DIR/passphrase.txt"
+diff -r -x "shasum.*.txt" -x "dircopy.key" -- "$DIR/src/subdir_a" "$DIR/restore-passphrase"
+
+# Signed shasum file, verified against the trusted key
+# Test keys only, not to be used for anything else
+rm -rf -- "$DIR/dst-signed"
+mkdir -p -- "$DIR/dst-signed"
+printf '%s\n' "untrusted comment: minisign secret key" \
+	"RWQAAEIyUpbkxFNeqDKIWIEfZxku8110bVpD03/Pa76CRNGUoDcAAAAAAAAAAAAAAAAAAAAAgebSHHHon8Rtf2yVFxOh4x90t5eUNRuValycfk3cYsHw8QP/pkHXPV1RRyVvG1e1zhtxRrgT/xYkE9IFWlq1niS7STlZ3gMwB9Bm2xDoOqD0mjaCzaFzoipZXdCWAxXEvgxxbK3Q5R0=" > "$DIR/minisign.key"
+printf '%s\n' "untrusted comment: minisign public key C49FE8711CD2E681" \
+	"RWSB5tIcceifxF1RRyVvG1e1zhtxRrgT/xYkE9IFWlq1niS7STlZ3gMw" > "$DIR/minisign.pub"
+target/release/dircopy -i "$DIR/src/subdir_c" -o "$DIR/dst-signed" --signing-key "$DIR/minisign.key"
+target/release/dirverify --silent --trusted-key "$DIR/minisign.pub" "$DIR/dst-signed"
+if target/release/dirverify --silent --trusted-key "$DIR/minisign.pub" "$DIR/dst"
+then
+	exit 1
+fi
+
+# Repair a damaged and a missing file from the source
+rm -rf -- "$DIR/dst-repair"
+mkdir -p -- "$DIR/dst-repair"
+target/release/dircopy -i "$DIR/src/subdir_c" -o "$DIR/dst-repair"
+printf x >> "$DIR/dst-repair/1025"
+rm -- "$DIR/dst-repair/1026"
+target/release/dirverify --silent --repair-from "$DIR/src/subdir_c" "$DIR/dst-repair"
+target/release/dirverify --silent "$DIR/dst-repair"
+find "$DIR/dst-repair" -name "repairs.*.txt" | grep -q .
+
+# Repair damaged files from recovery data, and fail beyond repair
+rm -rf -- "$DIR/dst-parity"
+mkdir -p -- "$DIR/dst-parity"
+target/release/dircopy -i "$DIR/src/subdir_c" -o "$DIR/dst-parity" --parity 10
+dd if=/dev/zero of="$DIR/dst-parity/1048576" bs=4096 seek=10 count=20 conv=notrunc
+printf x >> "$DIR/dst-parity/1025"
+target/release/dirverify --silent --repair-parity "$DIR/dst-parity"
+target/release/dirverify --silent "$DIR/dst-parity"
+dd if=/dev/zero of="$DIR/dst-parity/1048576" bs=4096 count=100 conv=notrunc
+if target/release/dirverify --silent --repair-parity "$DIR/dst-parity"
+then
+	exit 1
+fi
+
+# Benchmark copying and verifying
+rm -rf -- "$DIR/dst-benchmark"
+mkdir -p -- "$DIR/dst-benchmark"
+target/release/dircopy -i "$DIR/src/subdir_c" -o "$DIR/dst-benchmark" --benchmark --benchmark-block-sizes 64K,1M --benchmark-queue-sizes 2 | grep -q "^Recommended: "
+test -z "$(ls -A -- "$DIR/dst-benchmark")"
+target/release/dirverify --benchmark --benchmark-block-sizes 64K --benchmark-queue-sizes 2,4 "$DIR/dst-parity" | grep -q "^Recommended: "
+
+# Auto-tune copying and verifying
+rm -rf -- "$DIR/dst-tuned"
+mkdir -p -- "$DIR/dst-tuned"
+target/release/dircopy -i "$DIR/src" -o "$DIR/dst-tuned" --auto-tune | grep -q "^Auto-tuned: "
+target/release/dirverify --auto-tune "$DIR/dst-tuned" | grep -q "^\* Auto-tuned: "
+
+# Auto-tuning a single large file keeps the queue size it was copied with
+rm -rf -- "$DIR/src-tuned-large" "$DIR/dst-tuned-large"
+mkdir -p -- "$DIR/src-tuned-large" "$DIR/dst-tuned-large"
+dd if=/dev/urandom of="$DIR/src-tuned-large/large" count=256 bs=1M
+target/release/dircopy -i "$DIR/src-tuned-large" -o "$DIR/dst-tuned-large" --auto-tune --queue-size 3 | grep -q "^Auto-tuned: .*, queue size 3 "
+target/release/dirverify --auto-tune --queue-size 3 "$DIR/dst-tuned-large" | grep -q "^\* Auto-tuned: .*, queue size 3 "
+rm -rf -- "$DIR/src-tuned-large" "$DIR/dst-tuned-large"
+
+# Pipeline statistics name the bottleneck
+rm -rf -- "$DIR/dst-stalls"
+mkdir -p -- "$DIR/dst-stalls"
+target/release/dircopy -i "$DIR/src/subdir_c" -o "$DIR/dst-stalls" | grep -q "^Bottleneck: "
+target/release/dirverify "$DIR/dst-stalls" | grep -q "^\* Bottleneck: "
+
 # Verify failure upon missing file
 rm -- "$DIR/dst/subdir_a/subdir_b/1026"
 if target/release/dirverify --silent "$DIR/dst"
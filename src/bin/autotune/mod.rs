@@ -0,0 +1,160 @@
+// Adaptive tuning of block and queue sizes: each candidate setting is
+// used for a trial of about a second, and the fastest kept. Block sizes
+// may change between blocks of a file, queue sizes between files, so
+// queue size trials are made of whole files.
+// Shared between dircopy and dirverify; each binary uses a subset.
+#![allow(dead_code)]
+
+use std::time::Duration;
+
+use crate::texttools::i2s;
+use crate::texttools::size;
+
+// Bounds of the settings tried; all multiples of 4K, for direct I/O.
+pub const BLOCK_SIZES: [usize; 6] = [
+    64 * 1024,
+    128 * 1024,
+    256 * 1024,
+    1024 * 1024,
+    4 * 1024 * 1024,
+    8 * 1024 * 1024,
+];
+pub const QUEUE_SIZES: [usize; 4] = [2, 4, 10, 32];
+
+// Largest block size and queue size tried, starting at the given setting.
+pub fn max_setting(block_size: usize, queue_size: usize) -> (usize, usize) {
+    (
+        block_size.max(BLOCK_SIZES[BLOCK_SIZES.len() - 1]),
+        queue_size.max(QUEUE_SIZES[QUEUE_SIZES.len() - 1]),
+    )
+}
+
+// A trial lasts at least this long.
+const TRIAL_TIME: Duration = Duration::from_secs(1);
+
+enum Phase {
+    // Trying block_sizes[index] at the initial queue size.
+    Block(usize),
+    // Trying queue_sizes[index] at the fastest block size.
+    Queue(usize),
+    Done,
+}
+
+pub struct Tuner {
+    phase: Phase,
+    // Candidates, starting with the initial setting.
+    block_sizes: Vec<usize>,
+    queue_sizes: Vec<usize>,
+    block_size: usize,
+    queue_size: usize,
+    // Of the trial running.
+    trial_bytes: usize,
+    trial_time: Duration,
+    // Fastest setting of the phase so far, and its throughput.
+    best: Option<(usize, usize, f64)>,
+}
+
+impl Tuner {
+    // Starts at the given setting, then tries the other block sizes
+    // at queue_size, then the other queue sizes.
+    pub fn new(block_size: usize, queue_size: usize) -> Tuner {
+        let candidates = |first: usize, bounds: &[usize]| -> Vec<usize> {
+            let mut sizes = vec![first];
+            sizes.extend(bounds.iter().filter(|&&size| size != first));
+            sizes
+        };
+        Tuner {
+            phase: Phase::Block(0),
+            block_sizes: candidates(block_size, &BLOCK_SIZES),
+            queue_sizes: candidates(queue_size, &QUEUE_SIZES),
+            block_size,
+            queue_size,
+            trial_bytes: 0,
+            trial_time: Duration::ZERO,
+            best: None,
+        }
+    }
+
+    // Block size to use for the next block, and queue size for the next file.
+    pub fn setting(&self) -> (usize, usize) {
+        (self.block_size, self.queue_size)
+    }
+
+    pub fn done(&self) -> bool {
+        matches!(self.phase, Phase::Done)
+    }
+
+    // Whether block sizes are being tried, between blocks of a file.
+    pub fn tuning_blocks(&self) -> bool {
+        matches!(self.phase, Phase::Block(_))
+    }
+
+    // Account a block read with the current block size. Once block sizes
+    // are tried, files started at another queue size are not accounted.
+    pub fn record_block(&mut self, bytes: usize, elapsed: Duration) {
+        if self.tuning_blocks() {
+            self.record(bytes, elapsed);
+        }
+    }
+
+    // Account bytes processed with the current setting, moving on to
+    // the next setting once the trial is over. After the block phase,
+    // only whole files processed with setting() are to be accounted.
+    pub fn record(&mut self, bytes: usize, elapsed: Duration) {
+        if self.done() {
+            return;
+        }
+        self.trial_bytes += bytes;
+        self.trial_time += elapsed;
+        if self.trial_time < TRIAL_TIME || self.trial_bytes == 0 {
+            return;
+        }
+        let throughput = self.trial_bytes as f64 / self.trial_time.as_secs_f64();
+        self.trial_bytes = 0;
+        self.trial_time = Duration::ZERO;
+        if self.best.is_none_or(|(_, _, best)| throughput > best) {
+            self.best = Some((self.block_size, self.queue_size, throughput));
+        }
+        let (best_block_size, best_queue_size, best) = self.best.unwrap_or_default();
+        self.phase = match self.phase {
+            Phase::Block(index) if index + 1 < self.block_sizes.len() => {
+                self.block_size = self.block_sizes[index + 1];
+                Phase::Block(index + 1)
+            }
+            Phase::Block(_) => {
+                // The fastest block size is tried again within the queue
+                // phase, as one of its settings.
+                self.block_size = best_block_size;
+                self.queue_size = self.queue_sizes[0];
+                self.best = None;
+                Phase::Queue(0)
+            }
+            Phase::Queue(index) if index + 1 < self.queue_sizes.len() => {
+                self.queue_size = self.queue_sizes[index + 1];
+                Phase::Queue(index + 1)
+            }
+            Phase::Queue(_) | Phase::Done => {
+                self.block_size = best_block_size;
+                self.queue_size = best_queue_size;
+                self.best = Some((best_block_size, best_queue_size, best));
+                Phase::Done
+            }
+        };
+    }
+
+    // The chosen setting, for the summary.
+    pub fn describe(&self) -> String {
+        let mut description = format!(
+            "block size {}, queue size {}",
+            i2s(self.block_size),
+            self.queue_size
+        );
+        match (&self.phase, self.best) {
+            (Phase::Done, Some((_, _, throughput))) => {
+                description.push_str(&format!(" ({}/s)", size(throughput)));
+            }
+            _ => description.push_str(" (tuning incomplete)"),
+        }
+        description
+    }
+}
@@ -0,0 +1,185 @@
+// Benchmark runs over a matrix of block sizes, queue sizes and modes,
+// and a table of their throughput and CPU usage.
+// Shared between dircopy and dirverify; each binary uses a subset.
+#![allow(dead_code)]
+
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::texttools::i2s;
+use crate::texttools::s2i;
+use crate::texttools::size;
+
+// A way of running the pipeline, and the arguments selecting it.
+#[derive(Clone, Copy)]
+pub struct Mode {
+    pub name: &'static str,
+    pub args: &'static str,
+}
+
+#[derive(Clone, Copy)]
+pub struct Setting {
+    pub block_size: usize,
+    pub queue_size: usize,
+    pub mode: Mode,
+}
+
+impl Setting {
+    // The arguments to run with this setting.
+    pub fn args(&self) -> String {
+        let mut args = format!(
+            "--block-size {} --queue-size {}",
+            i2s(self.block_size),
+            self.queue_size
+        );
+        if !self.mode.args.is_empty() {
+            args.push(' ');
+            args.push_str(self.mode.args);
+        }
+        args
+    }
+}
+
+pub struct Sample {
+    pub setting: Setting,
+    pub bytes: usize,
+    pub elapsed: Duration,
+    pub cpu: Option<Duration>,
+}
+
+impl Sample {
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(1e-9)
+    }
+
+    // CPU time of all threads relative to wall time; above 100% when
+    // several threads are busy.
+    pub fn cpu_percent(&self) -> Option<f64> {
+        self.cpu
+            .map(|cpu| 100.0 * cpu.as_secs_f64() / self.elapsed.as_secs_f64().max(1e-9))
+    }
+}
+
+// Parse a comma separated list of sizes, e.g. "64K,1M".
+pub fn parse_sizes(list: &str) -> Result<Vec<usize>, String> {
+    let mut sizes = Vec::new();
+    for item in list.split(',') {
+        let item = item.trim();
+        if item.is_empty()
+            || !item
+                .chars()
+                .all(|c| c.is_ascii_digit() || "KMG".contains(c))
+        {
+            return Err(format!("Illegal size: '{}'", item));
+        }
+        let value = s2i(item.to_string());
+        if value == 0 {
+            return Err(format!("Illegal size: '{}'", item));
+        }
+        sizes.push(value);
+    }
+    Ok(sizes)
+}
+
+// Every combination of block size, queue size and mode.
+pub fn matrix(block_sizes: &[usize], queue_sizes: &[usize], modes: &[Mode]) -> Vec<Setting> {
+    let mut settings = Vec::new();
+    for mode in modes {
+        for block_size in block_sizes {
+            for queue_size in queue_sizes {
+                settings.push(Setting {
+                    block_size: *block_size,
+                    queue_size: *queue_size,
+                    mode: *mode,
+                });
+            }
+        }
+    }
+    settings
+}
+
+// User and system CPU time of the whole process, all threads included.
+#[cfg(unix)]
+pub fn cpu_time() -> Option<Duration> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+    let micros = |tv: libc::timeval| tv.tv_sec as u64 * 1_000_000 + tv.tv_usec as u64;
+    Some(Duration::from_micros(
+        micros(usage.ru_utime) + micros(usage.ru_stime),
+    ))
+}
+
+#[cfg(not(unix))]
+pub fn cpu_time() -> Option<Duration> {
+    None
+}
+
+// Run one setting; run returns the number of bytes processed.
+pub fn measure<F>(setting: Setting, run: F) -> Result<Sample, String>
+where
+    F: FnOnce(&Setting) -> Result<usize, String>,
+{
+    let cpu_start = cpu_time();
+    let start = Instant::now();
+    let bytes = run(&setting)?;
+    let elapsed = start.elapsed();
+    let cpu = match (cpu_start, cpu_time()) {
+        (Some(start), Some(end)) => Some(end.saturating_sub(start)),
+        _ => None,
+    };
+    Ok(Sample {
+        setting,
+        bytes,
+        elapsed,
+        cpu,
+    })
+}
+
+// Within 5% of the fastest, the setting using the least buffer memory,
+// then the least CPU, is recommended.
+pub fn recommend(samples: &[Sample]) -> Option<&Sample> {
+    let fastest = samples
+        .iter()
+        .map(|sample| sample.throughput())
+        .fold(0.0, f64::max);
+    samples
+        .iter()
+        .filter(|sample| sample.throughput() >= 0.95 * fastest)
+        .min_by(|a, b| {
+            let memory = |s: &Sample| s.setting.block_size * s.setting.queue_size;
+            memory(a)
+                .cmp(&memory(b))
+                .then(a.cpu.unwrap_or_default().cmp(&b.cpu.unwrap_or_default()))
+        })
+}
+
+pub fn print_table(samples: &[Sample]) {
+    let recommended = recommend(samples).map(|sample| sample.setting.args());
+    println!("| Block size | Queue size | Mode          | Throughput     | CPU     | Time     |");
+    println!("| ---------- | ---------- | ------------- | :------------- | :------ | :------- |");
+    for sample in samples {
+        let cpu = match sample.cpu_percent() {
+            Some(percent) => format!("{:.0}%", percent),
+            None => String::from("n/a"),
+        };
+        let marker = match recommended {
+            Some(ref args) if *args == sample.setting.args() => " *",
+            _ => "",
+        };
+        println!(
+            "| {:<10} | {:<10} | {:<13} | {:<14} | {:<7} | {:<8} |{}",
+            i2s(sample.setting.block_size),
+            sample.setting.queue_size,
+            sample.setting.mode.name,
+            format!("{}/s", size(sample.throughput())),
+            cpu,
+            format!("{:.3}s", sample.elapsed.as_secs_f64()),
+            marker
+        );
+    }
+    if let Some(args) = recommended {
+        println!("Recommended: {}", args);
+    }
+}
@@ -0,0 +1,126 @@
+// Hashes of fixed-size chunks of copied files, locating corruption within
+// a file that fails verification.
+// Shared between dircopy and dirverify; each binary uses a subset.
+#![allow(dead_code)]
+
+use std::path::Path;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::manifest;
+
+// Suffix of chunk hash files, beside the shasum file.
+pub const CHUNKS_SUFFIX: &str = ".chunks";
+
+// Name of the chunk hash file of a shasum file.
+pub fn chunks_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(CHUNKS_SUFFIX);
+    PathBuf::from(name)
+}
+
+// SHA-256 of each chunk of data written to it; the last chunk may be short.
+pub struct ChunkHasher {
+    chunk_size: u64,
+    filled: u64,
+    hasher: Sha256,
+    hashes: Vec<String>,
+}
+
+impl ChunkHasher {
+    pub fn new(chunk_size: u64) -> ChunkHasher {
+        ChunkHasher {
+            chunk_size,
+            filled: 0,
+            hasher: Sha256::new(),
+            hashes: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = data.len().min((self.chunk_size - self.filled) as usize);
+            self.hasher.update(&data[..n]);
+            self.filled += n as u64;
+            data = &data[n..];
+            if self.filled == self.chunk_size {
+                self.hashes
+                    .push(format!("{:x}", self.hasher.finalize_reset()));
+                self.filled = 0;
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<String> {
+        if self.filled != 0 {
+            self.hashes.push(format!("{:x}", self.hasher.finalize()));
+        }
+        self.hashes
+    }
+}
+
+// Chunk hashes of one file.
+pub struct FileChunks {
+    pub chunk_size: u64,
+    pub hashes: Vec<String>,
+}
+
+// One line of a chunk hash file.
+#[derive(Serialize, Deserialize)]
+struct ChunkRecord {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    path_bytes: Option<String>,
+    chunk_size: u64,
+    chunks: Vec<String>,
+}
+
+pub fn format_record(path: &Path, chunks: &FileChunks) -> Vec<u8> {
+    let (path, path_bytes) = manifest::json_name(path);
+    let record = ChunkRecord {
+        path,
+        path_bytes,
+        chunk_size: chunks.chunk_size,
+        chunks: chunks.hashes.clone(),
+    };
+    let mut line = serde_json::to_vec(&record).unwrap_or_default();
+    line.push(b'\n');
+    line
+}
+
+// The listed name, and the chunk hashes of the file.
+pub fn parse_record(line: &[u8]) -> Result<(Vec<u8>, FileChunks), String> {
+    let record: ChunkRecord = serde_json::from_slice(line).map_err(|e| e.to_string())?;
+    if record.chunk_size == 0 {
+        return Err(String::from("Chunk size 0"));
+    }
+    let name = manifest::name_from_json(record.path, record.path_bytes)?;
+    let chunks = FileChunks {
+        chunk_size: record.chunk_size,
+        hashes: record.chunks.iter().map(|h| h.to_lowercase()).collect(),
+    };
+    Ok((name, chunks))
+}
+
+// Byte ranges, end exclusive, of chunks that differ, adjacent chunks
+// merged. Chunks missing on either side differ; ranges past the end of
+// the shorter side run to the end of its last chunk.
+pub fn differing_ranges(expected: &FileChunks, actual: &[String]) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    let size = expected.chunk_size;
+    for i in 0..expected.hashes.len().max(actual.len()) {
+        if expected.hashes.get(i) == actual.get(i) {
+            continue;
+        }
+        let (start, end) = (i as u64 * size, (i as u64 + 1) * size);
+        match ranges.last_mut() {
+            Some(last) if last.1 == start => last.1 = end,
+            _ => ranges.push((start, end)),
+        }
+    }
+    ranges
+}
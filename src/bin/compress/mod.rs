@@ -0,0 +1,102 @@
+// zstd compression of copied files, and decompression when verifying.
+// Shared between dircopy and dirverify; each binary uses a subset.
+#![allow(dead_code)]
+
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use sha2::{Digest, Sha256};
+
+// Suffix of compressed files.
+pub const ZSTD_SUFFIX: &str = ".zst";
+
+// Name of the compressed file of path.
+pub fn zstd_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(ZSTD_SUFFIX);
+    PathBuf::from(name)
+}
+
+// A stage transforming data on its way to the destination file, e.g.
+// compression or encryption. Finishing writes any trailer, and returns
+// the writer of the file.
+pub trait Stage: Write + Send {
+    fn finish_stage(self: Box<Self>) -> io::Result<HashWriter>;
+}
+
+// Hashes what is written, i.e. the stored file.
+pub struct HashWriter {
+    file: File,
+    hasher: Sha256,
+}
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Stage for HashWriter {
+    fn finish_stage(self: Box<Self>) -> io::Result<HashWriter> {
+        Ok(*self)
+    }
+}
+
+impl HashWriter {
+    pub fn new(file: File) -> HashWriter {
+        HashWriter {
+            file,
+            hasher: Sha256::new(),
+        }
+    }
+
+    pub fn file(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    pub fn hex_digest(&self) -> String {
+        format!("{:x}", self.hasher.clone().finalize())
+    }
+}
+
+pub type ZstdWriter = zstd::stream::write::Encoder<'static, Box<dyn Stage>>;
+
+impl Stage for ZstdWriter {
+    fn finish_stage(self: Box<Self>) -> io::Result<HashWriter> {
+        (*self).finish()?.finish_stage()
+    }
+}
+
+pub fn zstd_writer(inner: Box<dyn Stage>, level: i32) -> io::Result<ZstdWriter> {
+    zstd::stream::write::Encoder::new(inner, level)
+}
+
+pub type ZstdReader<R> = zstd::stream::read::Decoder<'static, BufReader<R>>;
+
+pub fn zstd_reader<R: Read>(reader: R) -> io::Result<ZstdReader<R>> {
+    zstd::stream::read::Decoder::new(reader)
+}
+
+pub fn valid_level(level: i32) -> Result<(), String> {
+    let range = zstd::compression_level_range();
+    if !range.contains(&level) {
+        return Err(format!(
+            "Compression level {} out of range {}..={}",
+            level,
+            range.start(),
+            range.end()
+        ));
+    }
+    Ok(())
+}
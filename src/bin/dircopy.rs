@@ -1,31 +1,90 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::ffi::OsString;
 use std::fs;
-use std::fs::File;
 use std::fs::OpenOptions;
 use std::io;
 use std::io::IsTerminal;
 use std::io::Read;
 use std::io::Write;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::sync::mpsc::sync_channel;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
 use std::thread;
 use std::time::Instant;
 
 use chrono::prelude::*;
 use clap::Parser;
 use sha2::{Digest, Sha256};
+use unicode_normalization::UnicodeNormalization;
 
+mod autotune;
+mod benchmark;
+mod chunks;
+mod compress;
+mod encrypt;
+mod fsprofile;
+mod interrupt;
+mod iotools;
+mod lockfile;
+mod manifest;
+mod merkle;
+mod minisign;
+mod parity;
+mod stalls;
+mod tarstream;
+use autotune::Tuner;
+use benchmark::Mode;
+use chunks::ChunkHasher;
+use compress::HashWriter;
+use compress::Stage;
+use encrypt::Key;
+use fsprofile::TargetProfile;
+use iotools::pool_size;
+use iotools::AlignedBuffer;
+use iotools::BufferPool;
+use iotools::CacheOptions;
+use iotools::CacheTracker;
+use iotools::PooledBuffer;
+use manifest::Algorithm;
+use tarstream::MemberKind;
+use tarstream::TarReader;
+use tarstream::TarWriter;
 mod texttools;
+mod uring;
+use stalls::Pipeline;
+use stalls::Queue;
 use texttools::bandwidth;
 use texttools::s2i;
+use texttools::size;
+
+// Set when the archive is written to stdout, which then must carry nothing else.
+static INFO_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+// println!, unless stdout carries the archive.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if INFO_TO_STDERR.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
 
 /// A directory copy tool, that creates shasum*.txt (SHA256) files on the fly.
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Source directory to copy files from
+    /// Source directory to copy files from, or with --input-format tar
+    /// the archive to extract (- for stdin)
     #[arg(short, long)]
     input: std::path::PathBuf,
 
-    /// Destination directory to copy files to
+    /// Destination directory to copy files to, or with --output-format tar
+    /// the archive file to create (- for stdout)
     #[arg(short, long)]
     output: std::path::PathBuf,
 
@@ -41,6 +100,182 @@ struct Args {
     /// files.
     #[arg(long, default_value = "default")]
     overwrite_policy: String,
+
+    /// Bypass the page cache with O_DIRECT reads and writes (Linux).
+    /// Block size must be a multiple of 4K.
+    #[arg(long)]
+    direct_io: bool,
+
+    /// Advise the kernel of sequential access, and drop cached pages behind
+    /// the read and write positions (Linux).
+    #[arg(long)]
+    fadvise: bool,
+
+    /// Flush each written file and drop its pages from the page cache.
+    #[arg(long)]
+    drop_cache: bool,
+
+    /// When to fsync copied files, their directories and the shasum file:
+    /// none, file, directory or end.
+    #[arg(long, default_value = "end")]
+    fsync_policy: String,
+
+    /// Preallocate destination files to their full length before copying
+    /// (Linux). Reduces fragmentation, and fails fast when disk is full.
+    #[arg(long)]
+    preallocate: bool,
+
+    /// Do not check that the destination has enough free space before copying.
+    #[arg(long)]
+    no_space_check: bool,
+
+    /// How file data is transferred: stream, copy-file-range or reflink.
+    /// Kernel methods fall back to stream when not supported.
+    #[arg(long, default_value = "stream")]
+    copy_method: String,
+
+    /// What is hashed when data is copied by the kernel:
+    /// source (read concurrently) or destination (read after copy).
+    #[arg(long, default_value = "destination")]
+    fast_path_hash: String,
+
+    /// I/O backend used for streaming copies: threads or io-uring (Linux).
+    /// With io-uring, queue size is the number of blocks in flight.
+    #[arg(long, default_value = "threads")]
+    io_backend: String,
+
+    /// Unicode normalize destination file and directory names:
+    /// none, nfc or nfd. Every renamed entry is reported.
+    #[arg(long, default_value = "none")]
+    normalize_names: String,
+
+    /// Check names and file sizes against the destination file system
+    /// before copying: none, exfat, ntfs, fat32 or case-insensitive.
+    #[arg(long, default_value = "none")]
+    target_profile: String,
+
+    /// What to do with names invalid on the target profile: abort (report
+    /// and copy nothing) or rename (recorded in a renames*.txt file).
+    #[arg(long, default_value = "abort")]
+    incompatible_names: String,
+
+    /// Compress copied files, adding a .zst suffix: none or zstd.
+    /// The shasum file lists the hash of the original data, under the
+    /// original name.
+    #[arg(long, default_value = "none")]
+    compress: String,
+
+    /// zstd compression level, 1 (fast) to 22 (small).
+    #[arg(long, default_value_t = 3)]
+    compression_level: i32,
+
+    /// Also list the hash of each compressed or encrypted file, under
+    /// its stored name.
+    #[arg(long)]
+    compressed_hashes: bool,
+
+    /// Encrypt copied files with age, adding a .age suffix: none or age.
+    /// The shasum file lists the hash of the original data, under the
+    /// original name.
+    #[arg(long, default_value = "none")]
+    encryption: String,
+
+    /// File whose first line is the passphrase to encrypt or decrypt with.
+    #[arg(long)]
+    passphrase_file: Option<std::path::PathBuf>,
+
+    /// age identity file (age-keygen), to encrypt to its public keys or
+    /// decrypt with.
+    #[arg(long)]
+    key_file: Option<std::path::PathBuf>,
+
+    /// age public key (age1...) to encrypt to, may be repeated.
+    /// Decrypting requires the matching identity file.
+    #[arg(long)]
+    recipient: Vec<String>,
+
+    /// minisign secret key file to sign the shasum file with, writing a
+    /// detached .minisig signature beside it.
+    #[arg(long)]
+    signing_key: Option<std::path::PathBuf>,
+
+    /// File whose first line is the passphrase of an encrypted signing key.
+    #[arg(long)]
+    signing_passphrase_file: Option<std::path::PathBuf>,
+
+    /// Restore a compressed and/or encrypted copy: .age files are
+    /// decrypted and .zst files decompressed, under their original names.
+    #[arg(long)]
+    restore: bool,
+
+    /// Input format: directory, or tar (extract a tar archive into the
+    /// output directory, hashing each member as it is written).
+    #[arg(long, default_value = "directory")]
+    input_format: String,
+
+    /// Output format: directory, or tar (POSIX pax archive with each
+    /// file's SHA-256 in its pax header). The shasum file is written
+    /// beside the archive, or in the current directory for stdout.
+    #[arg(long, default_value = "directory")]
+    output_format: String,
+
+    /// With --output-format tar, also add the shasum file as the last
+    /// archive member.
+    #[arg(long)]
+    tar_manifest_member: bool,
+
+    /// Shasum file format: sha256sum (shasum.<date>.txt, checkable with
+    /// sha256sum -c), bsd (shasum.<date>.txt with "SHA256 (name) = hash"
+    /// lines, as by shasum --tag), or jsonl (shasum.<date>.jsonl, also
+    /// recording size, mtime and mode of each file, and where it was
+    /// copied from).
+    #[arg(long, default_value = "sha256sum")]
+    manifest_format: String,
+
+    /// Also write the tree hash of all files listed, one hash to compare
+    /// whole copies by, beside the shasum file (as <shasum file>.tree).
+    #[arg(long)]
+    tree_hash: bool,
+
+    /// Also write the SHA-256 of every chunk of this size (e.g. 64M) of
+    /// each file beside the shasum file (as <shasum file>.chunks), so that
+    /// dirverify can tell which byte ranges of a failing file differ.
+    #[arg(long)]
+    chunk_size: Option<String>,
+
+    /// Also write Reed-Solomon recovery data of each file beside it (as
+    /// <file>.parity), of this percentage (1-100) of its size. Damage to
+    /// up to about as much of the file can be repaired by
+    /// dirverify --repair-parity.
+    #[arg(long)]
+    parity: Option<u32>,
+
+    /// Benchmark instead of copying: copy the input directory into scratch
+    /// directories in the output directory, once for every combination of
+    /// benchmark block sizes, queue sizes and I/O backends, and print the
+    /// throughput and CPU usage of each, and the recommended setting.
+    #[arg(long)]
+    benchmark: bool,
+
+    /// Adapt block size and queue size while copying: try settings within
+    /// bounds for a second of copying each, then keep the fastest.
+    /// The chosen setting is printed at the end.
+    #[arg(long)]
+    auto_tune: bool,
+
+    /// Block sizes to benchmark, comma separated.
+    #[arg(long, default_value = "64K,128K,1M,8M")]
+    benchmark_block_sizes: String,
+
+    /// Queue sizes to benchmark, comma separated.
+    #[arg(long, default_value = "2,10,32")]
+    benchmark_queue_sizes: String,
+
+    /// Benchmark copying this much generated data (e.g. 4G), written to a
+    /// scratch directory in the output directory, rather than the files in
+    /// the input directory.
+    #[arg(long)]
+    benchmark_data: Option<String>,
 }
 
 trait OverwritePolicyTrait {
@@ -48,21 +283,17 @@ trait OverwritePolicyTrait {
 }
 
 enum OverwritePolicy {
-    OverwritePolicyNever,
-    OverwritePolicyAlways,
-    OverwritePolicyDefault,
+    Never,
+    Always,
+    Default,
 }
 
 impl OverwritePolicyTrait for OverwritePolicy {
     fn do_overwrite(&self, old_file: &std::fs::Metadata, new_file: &std::fs::Metadata) -> bool {
         match self {
-            OverwritePolicy::OverwritePolicyNever => {
-                return false;
-            }
-            OverwritePolicy::OverwritePolicyAlways => {
-                return true;
-            }
-            OverwritePolicy::OverwritePolicyDefault => {
+            OverwritePolicy::Never => false,
+            OverwritePolicy::Always => true,
+            OverwritePolicy::Default => {
                 if old_file.is_symlink() {
                     return false;
                 }
@@ -79,14 +310,229 @@ impl OverwritePolicyTrait for OverwritePolicy {
                         }
                     }
                 }
-                return true;
+                true
+            }
+        }
+    }
+}
+
+impl OverwritePolicy {
+    // As do_overwrite, for a new file known only by archive member size and mtime.
+    fn do_overwrite_member(&self, old_file: &std::fs::Metadata, len: u64, mtime: u64) -> bool {
+        match self {
+            OverwritePolicy::Never => false,
+            OverwritePolicy::Always => true,
+            OverwritePolicy::Default => {
+                if old_file.is_symlink() {
+                    return false;
+                }
+                if len <= old_file.len() {
+                    return false;
+                }
+                if let Ok(of) = old_file.modified() {
+                    if std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime) < of {
+                        return false;
+                    }
+                }
+                true
+            }
+        }
+    }
+}
+
+enum NameNormalization {
+    None,
+    // Composed form, e.g. Linux and Windows convention.
+    Nfc,
+    // Decomposed form, e.g. macOS HFS+ convention.
+    Nfd,
+}
+
+// Name a compressed and/or encrypted file is restored to.
+fn restored_path(path: &std::path::Path) -> std::path::PathBuf {
+    let mut path = path.to_path_buf();
+    for suffix in ["age", "zst"] {
+        if path.extension() == Some(OsStr::new(suffix)) {
+            path.set_extension("");
+        }
+    }
+    path
+}
+
+enum Compression {
+    None,
+    // With compression level.
+    Zstd(i32),
+}
+
+enum InputFormat {
+    // Copy from a directory tree.
+    Directory,
+    // Extract a tar archive, from a file or stdin.
+    Tar,
+}
+
+enum OutputFormat {
+    // Copy into a directory tree.
+    Directory,
+    // Write a POSIX pax archive, to a file or stdout.
+    Tar,
+}
+
+enum ManifestFormat {
+    // "<hash>  <path>" lines, as written by sha256sum.
+    Sha256sum,
+    // "SHA256 (<path>) = <hash>" lines, as written by shasum --tag.
+    Bsd,
+    // A JSON header, then one JSON record per file.
+    Jsonl,
+}
+
+enum IncompatibleNames {
+    // Report every problem, and copy nothing.
+    Abort,
+    // Replace invalid characters, and number colliding names.
+    Rename,
+}
+
+enum FsyncPolicy {
+    // Leave flushing to the operating system.
+    None,
+    // fsync each file, and its directory, before listing it in the manifest.
+    File,
+    // fsync files of a directory when the directory is completed.
+    Directory,
+    // fsync all files once copying is completed.
+    End,
+}
+
+enum CopyMethod {
+    // Read, hash and write data in userspace threads.
+    Stream,
+    // Let the kernel (or NFS server) copy the data.
+    CopyFileRange,
+    // Share data blocks with the source (Btrfs, XFS), copy_file_range otherwise.
+    Reflink,
+}
+
+enum IoBackend {
+    // Reader, hasher and writer threads connected by queues.
+    Threads,
+    // Batched reads and writes through io_uring, with registered buffers.
+    IoUring,
+}
+
+enum FastPathHash {
+    // Hash the source concurrently with the kernel copy.
+    Source,
+    // Hash the destination once copied.
+    Destination,
+}
+
+// Hash a file outside of the copy pipeline.
+fn sha_path(
+    path: &std::path::Path,
+    block_size: usize,
+    cache_options: &CacheOptions,
+) -> io::Result<String> {
+    let mut file = iotools::open_read(path, cache_options)?;
+    let mut tracker = CacheTracker::new(cache_options);
+    let mut heap_buf = AlignedBuffer::new(block_size);
+    let mut h1 = Sha256::new();
+    loop {
+        match iotools::read_full(&mut file, &mut heap_buf[0..block_size])? {
+            0 => break,
+            n => {
+                tracker.read(&file, n);
+                h1.update(&heap_buf[0..n]);
+            }
+        }
+    }
+    tracker.finish_read(&file);
+    Ok(format!("{:x}", h1.finalize()))
+}
+
+// Where the writer thread writes file data.
+enum Destination {
+    File(std::fs::File),
+    // Data of the current member of a tar archive.
+    Archive(TarWriter),
+    // Compressed and/or encrypted into a file.
+    Encoded(Box<dyn Stage>),
+    // The file an Encoded destination was written to, once finished.
+    Sealed(HashWriter),
+}
+
+fn write_blocks(
+    destination: Destination,
+    file_write_rx: Receiver<Message>,
+    cache_options: &CacheOptions,
+    sync_file: bool,
+    pipeline: &Pipeline,
+) -> Result<Destination, ()> {
+    let mut destination = destination;
+    let mut tracker = CacheTracker::new(cache_options);
+    let mut t = Instant::now();
+    loop {
+        let written = match pipeline.recv(Queue::FileWrite, &file_write_rx) {
+            Ok(Message::Block(block)) => {
+                t = pipeline.waiting(stalls::Stage::Write, t);
+                let written = match &mut destination {
+                    Destination::File(fo) => tracker.write(fo, &block),
+                    Destination::Archive(archive) => archive.write_all(&block),
+                    Destination::Encoded(writer) => writer.write_all(&block),
+                    Destination::Sealed(_) => Err(io::Error::other("write after finish")),
+                };
+                t = pipeline.busy(stalls::Stage::Write, t);
+                written
+            }
+            Ok(Message::Error) => {
+                return Err(());
+            }
+            Ok(Message::Done) => {
+                break;
+            }
+            Err(e) => {
+                eprintln!("Error T-FW: {}", e);
+                return Err(());
+            }
+        };
+        if let Err(e) = written {
+            eprintln!("Error T-FW: {}", e);
+            return Err(());
+        }
+    }
+    if let Destination::Encoded(writer) = destination {
+        match writer.finish_stage() {
+            Ok(sealed) => destination = Destination::Sealed(sealed),
+            Err(e) => {
+                eprintln!("Error T-FW: {}", e);
+                return Err(());
             }
         }
     }
+    let fo = match &mut destination {
+        Destination::File(fo) => fo,
+        Destination::Archive(_) => return Ok(destination),
+        Destination::Encoded(_) => return Err(()),
+        Destination::Sealed(writer) => writer.file(),
+    };
+    if let Err(e) = tracker.finish_write(fo) {
+        eprintln!("Error T-FW: {}", e);
+        return Err(());
+    }
+    if sync_file {
+        if let Err(e) = fo.sync_all() {
+            eprintln!("Error T-FW: fsync: {}", e);
+            return Err(());
+        }
+    }
+    Ok(destination)
 }
 
+// Buffers are returned to the pool once both hasher and writer dropped them.
 enum Message {
-    Block(Vec<u8>),
+    Block(Arc<PooledBuffer>),
     Done,
     Error,
 }
@@ -105,8 +551,72 @@ struct DirCopy {
     start_of_copying: Instant,
     last_update: Instant,
     overwrite_policy: OverwritePolicy,
+    cache_options: CacheOptions,
+    fsync_policy: FsyncPolicy,
+    // Files and directories not yet known to be durable.
+    pending_sync: Vec<std::path::PathBuf>,
+    // Manifest lines waiting for their files to become durable.
+    pending_manifest: Vec<Vec<u8>>,
+    preallocate: bool,
+    copy_method: CopyMethod,
+    fast_path_hash: FastPathHash,
+    fast_path_warned: bool,
+    io_backend: IoBackend,
+    // Recycled between files.
+    pool: Option<BufferPool>,
+    normalize_names: NameNormalization,
+    target_profile: TargetProfile,
+    incompatible_names: IncompatibleNames,
+    // Renamed entries, as source path and relative destination path.
+    renames: Vec<(std::path::PathBuf, std::path::PathBuf)>,
+    compression: Compression,
+    compressed_hashes: bool,
+    key: Option<Key>,
+    // The key of the copy being made or restored, unlocked by key.
+    copy_key: Option<Key>,
+    encrypt: bool,
+    restore: bool,
+    signing_key: Option<minisign::SecretKey>,
+    input_format: InputFormat,
+    output_format: OutputFormat,
+    // Open while copying into a tar archive.
+    archive: Option<TarWriter>,
+    tar_manifest_member: bool,
+    manifest_format: ManifestFormat,
+    // Of all files listed, with --tree-hash.
+    tree: Option<merkle::Tree>,
+    // With --chunk-size: chunk hashes of the file last streamed, and
+    // lines not yet written to the chunk hash file.
+    chunk_size: Option<u64>,
+    chunk_hashes: Vec<String>,
+    pending_chunks: Vec<Vec<u8>>,
+    chunks_file: Option<std::fs::File>,
+    // With --parity: percentage, and the recovery data of the file being
+    // copied, handed to the hasher thread.
+    parity: Option<u32>,
+    parity_encoder: Option<parity::Encoder>,
+    // With --auto-tune: the block and queue size for the next file.
+    auto_tune: Option<Tuner>,
+    // Time spent by the stream threads, over all files.
+    pipeline: Arc<Pipeline>,
+    // Destination of the file removed when the run was interrupted.
+    rolled_back: Option<std::path::PathBuf>,
+    // Copy small files through io_uring several at a time; cleared if
+    // the kernel cannot.
+    batch_files: bool,
+}
+
+// A file of at most one block, waiting to be copied with others.
+struct SmallFile {
+    input: std::path::PathBuf,
+    output: std::path::PathBuf,
+    rel: std::path::PathBuf,
+    source: fs::Metadata,
 }
 
+// Most small files copied through io_uring in one go.
+const URING_BATCH_FILES: usize = 1024;
+
 impl DirCopy {
     fn emit_debug_message(&mut self) -> bool {
         if !self.debug {
@@ -116,7 +626,7 @@ impl DirCopy {
         if update {
             self.last_update = Instant::now();
         }
-        return update;
+        update
     }
 
     fn debug_message(&self) -> String {
@@ -128,7 +638,7 @@ impl DirCopy {
         } else {
             while size > 0 {
                 let reminder = size % 1024;
-                size = size / 1024;
+                size /= 1024;
                 vec.push(reminder);
             }
         }
@@ -145,7 +655,7 @@ impl DirCopy {
             }
             let tmp: String = format!("{}{} ", reminder, s);
             result = result + &tmp;
-            max = max - 1;
+            max -= 1;
             if max == 0 {
                 break;
             }
@@ -153,257 +663,714 @@ impl DirCopy {
         let seconds = self.start_of_copying.elapsed().as_secs();
         result = result + "| " + &bandwidth(self.read_bytes, seconds);
 
-        let tmp: String = format!(" | {} files      ", self.read_files);
+        let tmp: String = format!(" | {} files", self.read_files);
         result = result + &tmp;
+        if let Some(stage) = self.pipeline.bottleneck() {
+            result = result + " | bottleneck: " + stage;
+        }
+        result += "      ";
 
-        return result;
+        result
     }
 
+    // Returns the SHA-256 of the data, and of the stored file if compressing
+    // or encrypting.
     fn copy(
         &mut self,
         input: std::path::PathBuf,
         output: std::path::PathBuf,
-    ) -> Result<String, io::Error> {
+    ) -> Result<(String, Option<String>), io::Error> {
+        let cache_options: CacheOptions = self.cache_options;
+
+        if !matches!(self.copy_method, CopyMethod::Stream) {
+            if let Some(hash) = self.copy_kernel(&input, &output)? {
+                self.read_files += 1;
+                return Ok((hash, None));
+            }
+        }
+
+        let fi = iotools::open_read(&input, &cache_options)?;
+        let mut fo = iotools::create_write(&output, &cache_options)?;
+
+        let len = fi.metadata()?.len();
+        self.preallocate(&fo, len, &output)?;
+
+        if let Some(percent) = self.parity {
+            let path = parity::parity_path(&output);
+            self.parity_encoder = Some(parity::Encoder::create(&path, len, percent)?);
+        }
+
+        if let IoBackend::IoUring = self.io_backend {
+            let result = self.copy_uring(&fi, &mut fo)?;
+            self.read_files += 1;
+            return Ok((result, None));
+        }
+
+        if self.restore {
+            let reader = self.decoder(&input, &fi)?;
+            let (result, _) = self.stream(reader, Some(&fi), Destination::File(fo))?;
+            self.read_files += 1;
+            return Ok((result, None));
+        }
+
+        let destination = self.encoder(fo)?;
+        let (result, destination) = self.stream(&fi, Some(&fi), destination)?;
+        let stored = match destination {
+            Destination::Sealed(writer) => Some(writer.hex_digest()),
+            _ => None,
+        };
+        self.read_files += 1;
+        Ok((result, stored))
+    }
+
+    // Compression, then encryption, of data written to the file.
+    fn encoder(&self, fo: std::fs::File) -> io::Result<Destination> {
+        if !self.encrypt && matches!(self.compression, Compression::None) {
+            return Ok(Destination::File(fo));
+        }
+        let mut stage: Box<dyn Stage> = Box::new(HashWriter::new(fo));
+        if let (true, Some(key)) = (self.encrypt, &self.copy_key) {
+            stage = Box::new(encrypt::age_writer(stage, key)?);
+        }
+        if let Compression::Zstd(level) = self.compression {
+            stage = Box::new(compress::zstd_writer(stage, level)?);
+        }
+        Ok(Destination::Encoded(stage))
+    }
+
+    // Decryption, then decompression, of a file being restored, as told
+    // by its suffixes.
+    fn decoder<'a>(
+        &self,
+        input: &std::path::Path,
+        fi: &'a std::fs::File,
+    ) -> io::Result<Box<dyn Read + Send + 'a>> {
+        let mut reader: Box<dyn Read + Send + 'a> = Box::new(io::BufReader::new(fi));
+        let mut path = input.to_path_buf();
+        if path.extension() == Some(OsStr::new("age")) {
+            let key = match &self.copy_key {
+                Some(key) => key,
+                None => {
+                    return Err(io::Error::other(format!(
+                        "{}: no key to decrypt with",
+                        input.display()
+                    )))
+                }
+            };
+            reader = Box::new(encrypt::age_reader(reader, key)?);
+            path.set_extension("");
+        }
+        if path.extension() == Some(OsStr::new("zst")) {
+            reader = Box::new(compress::zstd_reader(reader)?);
+        }
+        Ok(reader)
+    }
+
+    // Read, hash and write one file through the reader, router, hasher
+    // and writer threads. The destination is handed back once written.
+    // input_file, if any, is the file behind fi, for page cache control.
+    fn stream<R: Read + Send>(
+        &mut self,
+        mut fi: R,
+        input_file: Option<&std::fs::File>,
+        destination: Destination,
+    ) -> Result<(String, Destination), io::Error> {
+        self.apply_tuning();
+        let tuning_start = (Instant::now(), self.read_bytes);
         let block_size: usize = self.block_size;
         let queue_size: usize = self.queue_size;
-
-        let mut fi = File::open(input)?;
-        let mut fo = File::create(output)?;
+        let cache_options: CacheOptions = self.cache_options;
+        // Moved to the reader, which tunes the block size between blocks.
+        // Queue sizes are tuned between files, as channels and buffers
+        // are set up for a file.
+        let mut tuner = self.auto_tune.take();
+        let tune_file = tuner.as_ref().is_some_and(|tuner| !tuner.tuning_blocks());
+        let sync_file = matches!(self.fsync_policy, FsyncPolicy::File);
+        let chunk_size = self.chunk_size;
+        let mut parity_encoder = self.parity_encoder.take();
+        let sync_parity = !matches!(self.fsync_policy, FsyncPolicy::None);
 
         let (read_tx, read_rx) = sync_channel::<Message>(queue_size);
         let (sha_tx, sha_rx) = sync_channel::<Message>(queue_size);
         let (file_write_tx, file_write_rx) = sync_channel::<Message>(queue_size);
         let (status_tx, status_rx) = sync_channel::<StatusMessage>(queue_size);
 
-        let read_thread = thread::spawn(move || {
-            let mut failed = true;
-            let mut heap_buf: Vec<u8> = Vec::with_capacity(block_size);
-            heap_buf.resize(block_size, 0x00);
-            loop {
-                match fi.read(&mut heap_buf[0..block_size]) {
-                    Ok(0) => {
-                        failed = false;
+        let pool = match self.pool.take() {
+            Some(pool) => pool,
+            None => BufferPool::new(pool_size(queue_size), block_size),
+        };
+        let pipeline = Arc::clone(&self.pipeline);
+        let pipeline: &Pipeline = &pipeline;
+        // Files are rolled back when interrupted, archive members are
+        // finished, as the archive cannot be rolled back.
+        let interruptible = !matches!(destination, Destination::Archive(_));
+
+        let result = thread::scope(|scope| {
+            let read_thread = scope.spawn(move || -> (BufferPool, Option<Tuner>) {
+                let mut failed = true;
+                let mut tracker = CacheTracker::new(&cache_options);
+                let mut block_size = block_size;
+                let mut t = Instant::now();
+                let mut tuned = t;
+                loop {
+                    if interruptible && interrupt::interrupted() {
                         break;
                     }
-                    Ok(n) => {
-                        if let Err(e) = read_tx.send(Message::Block(heap_buf[0..n].to_vec())) {
+                    // Blocks until hasher and writer are done with a buffer.
+                    let mut block = match pool.take() {
+                        Some(block) => block,
+                        None => break,
+                    };
+                    t = pipeline.waiting(stalls::Stage::Read, t);
+                    block.resize(block_size);
+                    match iotools::read_full(&mut fi, &mut block[0..block_size]) {
+                        Ok(0) => {
+                            failed = false;
+                            break;
+                        }
+                        Ok(n) => {
+                            if let Some(file) = input_file {
+                                tracker.read(file, n);
+                            }
+                            if let Some(tuner) = tuner.as_mut() {
+                                tuner.record_block(n, tuned.elapsed());
+                                tuned = Instant::now();
+                                block_size = tuner.setting().0;
+                            }
+                            block.truncate(n);
+                            t = pipeline.busy(stalls::Stage::Read, t);
+                            let block = Message::Block(Arc::new(block));
+                            if let Err(e) = pipeline.send(Queue::Read, &read_tx, block) {
+                                eprintln!("Error: {}", e);
+                                break;
+                            }
+                            t = pipeline.waiting(stalls::Stage::Read, t);
+                        }
+                        Err(e) => {
                             eprintln!("Error: {}", e);
                             break;
                         }
                     }
-                    Err(e) => {
+                }
+                if let Some(file) = input_file {
+                    tracker.finish_read(file);
+                }
+                if failed {
+                    if let Err(e) = read_tx.send(Message::Error) {
                         eprintln!("Error: {}", e);
-                        break;
                     }
+                    return (pool, tuner);
                 }
-            }
-            if failed {
-                if let Err(e) = read_tx.send(Message::Error) {
+                if let Err(e) = read_tx.send(Message::Done) {
                     eprintln!("Error: {}", e);
                 }
-                return;
-            }
-            if let Err(e) = read_tx.send(Message::Done) {
-                eprintln!("Error: {}", e);
-            }
-        });
+                (pool, tuner)
+            });
 
-        let router_thread = thread::spawn(move || {
-            let mut err = false;
-            loop {
-                match read_rx.recv() {
-                    Ok(Message::Block(block)) => {
-                        if let Err(e) = sha_tx.send(Message::Block(block.clone())) {
-                            eprintln!("Error: {}", e);
-                            err = true;
+            let router_thread = scope.spawn(move || {
+                let mut err = false;
+                loop {
+                    match pipeline.recv(Queue::Read, &read_rx) {
+                        Ok(Message::Block(block)) => {
+                            // Hasher and writer share the block; no data is copied.
+                            let len = block.len();
+                            let shared = Message::Block(Arc::clone(&block));
+                            if let Err(e) = pipeline.send(Queue::Sha, &sha_tx, shared) {
+                                eprintln!("Error: {}", e);
+                                err = true;
+                            }
+                            let block = Message::Block(block);
+                            if let Err(e) = pipeline.send(Queue::FileWrite, &file_write_tx, block) {
+                                eprintln!("Error: {}", e);
+                                err = true;
+                            }
+                            if let Err(e) = status_tx.send(StatusMessage::StatusIncBlock(len)) {
+                                eprintln!("Error: {}", e);
+                                err = true;
+                            }
+                            if err {
+                                break;
+                            }
                         }
-                        if let Err(e) = file_write_tx.send(Message::Block(block.clone())) {
-                            eprintln!("Error: {}", e);
-                            err = true;
+                        Ok(Message::Done) => {
+                            break;
                         }
-                        if let Err(e) = status_tx.send(StatusMessage::StatusIncBlock(block.len())) {
-                            eprintln!("Error: {}", e);
+                        Ok(Message::Error) => {
                             err = true;
+                            break;
                         }
-                        if err {
+                        Err(e) => {
+                            eprintln!("Error: {}", e);
                             break;
                         }
                     }
-                    Ok(Message::Done) => {
-                        break;
+                }
+                if err {
+                    if let Err(e) = sha_tx.send(Message::Error) {
+                        eprintln!("Error: {}", e);
                     }
-                    Ok(Message::Error) => {
-                        err = true;
-                        break;
+                    if let Err(e) = file_write_tx.send(Message::Error) {
+                        eprintln!("Error: {}", e);
                     }
-                    Err(e) => {
+                } else {
+                    if let Err(e) = sha_tx.send(Message::Done) {
+                        eprintln!("Error: {}", e);
+                    }
+                    if let Err(e) = file_write_tx.send(Message::Done) {
                         eprintln!("Error: {}", e);
-                        break;
                     }
                 }
-            }
-            if err {
-                if let Err(e) = sha_tx.send(Message::Error) {
+                if let Err(e) = status_tx.send(StatusMessage::StatusDone) {
                     eprintln!("Error: {}", e);
                 }
-                if let Err(e) = file_write_tx.send(Message::Error) {
-                    eprintln!("Error: {}", e);
+            });
+
+            let sha_thread = scope.spawn(move || -> Result<(String, Vec<String>), ()> {
+                let mut h1 = Sha256::new();
+                let mut chunk_hasher = chunk_size.map(ChunkHasher::new);
+                let mut incomplete = true;
+                let mut incomplete_parity = false;
+                let mut t = Instant::now();
+                loop {
+                    match pipeline.recv(Queue::Sha, &sha_rx) {
+                        Ok(Message::Block(block)) => {
+                            t = pipeline.waiting(stalls::Stage::Hash, t);
+                            h1.update(&block[..]);
+                            if let Some(chunk_hasher) = chunk_hasher.as_mut() {
+                                chunk_hasher.update(&block[..]);
+                            }
+                            if let Some(encoder) = parity_encoder.as_mut() {
+                                if let Err(e) = encoder.update(&block[..]) {
+                                    eprintln!("Error writing recovery data: {}", e);
+                                    parity_encoder = None;
+                                    incomplete_parity = true;
+                                }
+                            }
+                            t = pipeline.busy(stalls::Stage::Hash, t);
+                        }
+                        Ok(Message::Error) => {
+                            break;
+                        }
+                        Ok(Message::Done) => {
+                            incomplete = false;
+                            break;
+                        }
+                        Err(e) => {
+                            eprintln!("Error T-SHA: {}", e);
+                            break;
+                        }
+                    }
                 }
-            } else {
-                if let Err(e) = sha_tx.send(Message::Done) {
-                    eprintln!("Error: {}", e);
+                if incomplete || incomplete_parity {
+                    return Err(());
                 }
-                if let Err(e) = file_write_tx.send(Message::Done) {
-                    eprintln!("Error: {}", e);
+                if let Some(encoder) = parity_encoder {
+                    if let Err(e) = encoder.finish(sync_parity) {
+                        eprintln!("Error writing recovery data: {}", e);
+                        return Err(());
+                    }
                 }
-            }
-            if let Err(e) = status_tx.send(StatusMessage::StatusDone) {
-                eprintln!("Error: {}", e);
-            }
-        });
+                let digest = h1.finalize();
+                let strdigest = format!("{:x}", digest);
+                let chunk_hashes = match chunk_hasher {
+                    Some(chunk_hasher) => chunk_hasher.finish(),
+                    None => Vec::new(),
+                };
+                Ok((strdigest, chunk_hashes))
+            });
+
+            let file_write_thread = scope.spawn(move || -> Result<Destination, ()> {
+                write_blocks(
+                    destination,
+                    file_write_rx,
+                    &cache_options,
+                    sync_file,
+                    pipeline,
+                )
+            });
 
-        let sha_thread = thread::spawn(move || -> Result<String, ()> {
-            let mut h1 = Sha256::new();
-            let mut incomplete = true;
+            let mut stderr = io::stderr();
             loop {
-                match sha_rx.recv() {
-                    Ok(Message::Block(block)) => {
-                        h1.update(&block);
-                    }
-                    Ok(Message::Error) => {
+                match status_rx.recv() {
+                    Ok(StatusMessage::StatusDone) => {
                         break;
                     }
-                    Ok(Message::Done) => {
-                        incomplete = false;
-                        break;
+                    Ok(StatusMessage::StatusIncBlock(u)) => {
+                        self.read_bytes += u;
+
+                        if self.emit_debug_message() {
+                            let debug_msg = self.debug_message();
+                            let _ = stderr.write(debug_msg.as_bytes());
+                            let _ = stderr.flush();
+                        }
                     }
                     Err(e) => {
-                        eprintln!("Error T-SHA: {}", e);
-                        break;
+                        eprintln!("Error status loop: {}", e);
                     }
                 }
             }
-            if incomplete {
-                return Err(());
+
+            let mut failed = true;
+            let mut result: String = "".to_string();
+
+            match read_thread.join() {
+                Ok((pool, tuner)) => {
+                    self.pool = Some(pool);
+                    self.auto_tune = tuner;
+                }
+                Err(_) => panic!("Failure to join read thread"),
             }
-            let digest = h1.finalize();
-            let strdigest = format!("{:x}", digest);
-            return Ok(strdigest);
-        });
+            if router_thread.join().is_err() {
+                panic!("Failure to join router thread");
+            }
+            let write_result = match file_write_thread.join() {
+                Ok(r) => r,
+                Err(_) => panic!("Failure to join file write thread"),
+            };
 
-        let file_write_thread = thread::spawn(move || loop {
-            match file_write_rx.recv() {
-                Ok(Message::Block(block)) => {
-                    if let Err(e) = fo.write_all(&block) {
-                        eprintln!("Error T-FW: {}", e);
-                        break;
+            let sha_result: Result<(String, Vec<String>), ()> = match sha_thread.join() {
+                Ok(s) => s,
+                Err(_) => panic!("Failure to join sha thread"),
+            };
+            match sha_result {
+                Ok((s, chunk_hashes)) => {
+                    if s.len() == 64 {
+                        result = s;
+                        self.chunk_hashes = chunk_hashes;
+                        failed = false;
+                    } else {
+                        eprintln!("Bad SHA-256 received: '{}'", s);
                     }
                 }
-                Ok(Message::Error) => {
-                    break;
-                }
-                Ok(Message::Done) => {
-                    break;
-                }
-                Err(e) => {
-                    eprintln!("Error T-FW: {}", e);
-                    break;
+                Err(_) => {
+                    if !interrupt::interrupted() {
+                        eprintln!("SHA-thread completed errornously!");
+                    }
                 }
             }
-        });
-
-        let mut stderr = io::stderr();
-        loop {
-            match status_rx.recv() {
-                Ok(StatusMessage::StatusDone) => {
-                    break;
-                }
-                Ok(StatusMessage::StatusIncBlock(u)) => {
-                    self.read_bytes += u;
 
-                    if self.emit_debug_message() {
-                        let debug_msg = self.debug_message();
-                        let _ = stderr.write(debug_msg.as_bytes());
-                        let _ = stderr.flush();
+            let destination = match write_result {
+                Ok(destination) => destination,
+                Err(_) => {
+                    if !interrupt::interrupted() {
+                        eprintln!("File write thread completed errornously!");
                     }
+                    return Err(std::io::Error::from(std::io::ErrorKind::Interrupted));
                 }
-                Err(e) => {
-                    eprintln!("Error status loop: {}", e);
-                }
-            }
-        }
+            };
 
-        let mut failed = true;
-        let mut result: String = "".to_string();
+            if failed {
+                return Err(std::io::Error::from(std::io::ErrorKind::Interrupted));
+            }
 
-        if let Err(_) = read_thread.join() {
-            panic!("Failure to join read thread");
-        }
-        if let Err(_) = router_thread.join() {
-            panic!("Failure to join router thread");
-        }
-        if let Err(_) = file_write_thread.join() {
-            panic!("Failure to join file write thread");
+            Ok((result, destination))
+        });
+        if tune_file {
+            self.record_tuning(tuning_start);
         }
+        result
+    }
 
-        let sha_result: Result<String, ()>;
-        match sha_thread.join() {
-            Ok(s) => {
-                sha_result = s;
-            }
-            Err(_) => panic!("Failure to join sha thread"),
-        }
-        match sha_result {
-            Ok(s) => {
-                if s.len() == 64 {
-                    result = s;
-                    failed = false;
-                } else {
-                    eprintln!("Bad SHA-256 received: '{}'", s);
-                }
-            }
-            Err(_) => {
-                eprintln!("SHA-thread completed errornously!");
+    // With --auto-tune, switch to the tuner's setting before a file.
+    fn apply_tuning(&mut self) {
+        if let Some(tuner) = &self.auto_tune {
+            let (block_size, queue_size) = tuner.setting();
+            if (block_size, queue_size) != (self.block_size, self.queue_size) {
+                self.block_size = block_size;
+                self.queue_size = queue_size;
+                self.pool = None;
             }
         }
+    }
 
-        if failed {
-            return Err(std::io::Error::from(std::io::ErrorKind::Interrupted));
+    // Account the file copied since start to the tuner's trial;
+    // io_uring transfers a file at one block size, and queue sizes
+    // apply to whole files.
+    fn record_tuning(&mut self, start: (Instant, usize)) {
+        let bytes = self.read_bytes - start.1;
+        if let Some(tuner) = self.auto_tune.as_mut() {
+            tuner.record(bytes, start.0.elapsed());
         }
+    }
 
-        self.read_files += 1;
-
+    fn copy_uring(&mut self, fi: &std::fs::File, fo: &mut std::fs::File) -> io::Result<String> {
+        self.apply_tuning();
+        let tuning_start = (Instant::now(), self.read_bytes);
+        let cache_options = self.cache_options;
+        let len = fi.metadata()?.len();
+        let result = uring::with_ring(self.queue_size, self.block_size, |ring| {
+            let mut progress = |n: usize| {
+                self.read_bytes += n;
+                if self.emit_debug_message() {
+                    let debug_msg = self.debug_message();
+                    let mut stderr = io::stderr();
+                    let _ = stderr.write(debug_msg.as_bytes());
+                    let _ = stderr.flush();
+                }
+            };
+            uring::transfer(
+                ring,
+                fi,
+                Some(fo),
+                len,
+                cache_options.direct_io,
+                &mut progress,
+            )
+        })?;
+        CacheTracker::new(&cache_options).finish_read(fi);
+        CacheTracker::new(&cache_options).finish_write(fo)?;
+        if let FsyncPolicy::File = self.fsync_policy {
+            fo.sync_all()?;
+        }
+        self.record_tuning(tuning_start);
         Ok(result)
     }
 
-    fn copy_directory(
+    // Copy data inside the kernel (reflink or copy_file_range), hashing
+    // the source concurrently or the destination afterwards.
+    // Returns None if the fast path is unavailable and nothing was copied.
+    fn copy_kernel(
         &mut self,
-        input: std::path::PathBuf,
-        output: std::path::PathBuf,
-    ) -> io::Result<()> {
-        let rel = std::path::PathBuf::new();
+        input: &std::path::Path,
+        output: &std::path::Path,
+    ) -> io::Result<Option<String>> {
+        let block_size = self.block_size;
+        let cache_options = self.cache_options;
 
-        let now = Local::now();
-        let date_string = now.format("shasum.%Y-%m-%d.%H.%M.%S.txt").to_string();
-        let mut foptions = OpenOptions::new();
-        let _ = foptions.write(true);
-        let _ = foptions.create_new(true);
+        let fi = iotools::open_read(input, &cache_options)?;
+        let mut fo = iotools::create_write(output, &cache_options)?;
+        let len = fi.metadata()?.len();
 
-        let mut path_shasum = output.clone();
-        path_shasum.push(date_string);
+        let source_hasher = match self.fast_path_hash {
+            FastPathHash::Source => {
+                let input = input.to_path_buf();
+                Some(thread::spawn(move || {
+                    sha_path(&input, block_size, &cache_options)
+                }))
+            }
+            FastPathHash::Destination => None,
+        };
 
-        let mut shasum_file;
-        match foptions.open(&path_shasum) {
-            Ok(file) => {
-                shasum_file = file;
+        let mut copied = false;
+        if let CopyMethod::Reflink = self.copy_method {
+            match iotools::reflink(&fi, &fo) {
+                Ok(()) => copied = true,
+                Err(e) => self.fast_path_unavailable("reflink", e)?,
             }
-            Err(e) => {
-                return Err(e);
+        }
+        if !copied {
+            self.preallocate(&fo, len, output)?;
+            match iotools::copy_file_range(&fi, &fo, len) {
+                Ok(_) => copied = true,
+                Err(e) => self.fast_path_unavailable("copy_file_range", e)?,
+            }
+        }
+        if !copied {
+            if let Some(thread) = source_hasher {
+                let _ = thread.join();
             }
+            return Ok(None);
         }
-        println!("Writing SHA256 sums to: {}", path_shasum.display());
 
-        let result = self.copy_dir(&mut shasum_file, input, rel, output);
+        CacheTracker::new(&cache_options).finish_write(&mut fo)?;
+        if let FsyncPolicy::File = self.fsync_policy {
+            fo.sync_all()?;
+        }
+        drop(fo);
+
+        let hash = match source_hasher {
+            Some(thread) => match thread.join() {
+                Ok(r) => r?,
+                Err(_) => panic!("Failure to join sha thread"),
+            },
+            None => sha_path(output, block_size, &cache_options)?,
+        };
+        self.read_bytes += len as usize;
+        if self.emit_debug_message() {
+            let debug_msg = self.debug_message();
+            let mut stderr = io::stderr();
+            let _ = stderr.write(debug_msg.as_bytes());
+            let _ = stderr.flush();
+        }
+        Ok(Some(hash))
+    }
+
+    // Decide if a failed fast path falls back to the next copy method.
+    // Preallocation only helps performance, so the file system not
+    // supporting it is a warning, after which files are not preallocated.
+    fn preallocate(
+        &mut self,
+        fo: &std::fs::File,
+        len: u64,
+        output: &std::path::Path,
+    ) -> io::Result<()> {
+        if !self.preallocate {
+            return Ok(());
+        }
+        match iotools::preallocate(fo, len) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                eprintln!(
+                    "Warning: preallocation not supported on {} ({}), continuing without",
+                    output.display(),
+                    e
+                );
+                self.preallocate = false;
+                Ok(())
+            }
+            Err(e) => {
+                eprintln!("Error preallocating {}: {}", output.display(), e);
+                Err(e)
+            }
+        }
+    }
+
+    fn fast_path_unavailable(&mut self, method: &str, e: io::Error) -> io::Result<()> {
+        if !iotools::is_unsupported(&e) {
+            return Err(e);
+        }
+        if !self.fast_path_warned {
+            eprintln!("Note: {} not available ({}), falling back", method, e);
+            self.fast_path_warned = true;
+        }
+        Ok(())
+    }
+
+    // Copy input to destination, writing the shasum file to output,
+    // which is the destination unless writing an archive.
+    fn copy_directory(
+        &mut self,
+        input: std::path::PathBuf,
+        destination: &std::path::Path,
+        output: std::path::PathBuf,
+    ) -> io::Result<()> {
+        let rel = std::path::PathBuf::new();
+
+        let now = Local::now();
+        let date_string = match self.manifest_format {
+            ManifestFormat::Sha256sum | ManifestFormat::Bsd => {
+                now.format("shasum.%Y-%m-%d.%H.%M.%S.txt")
+            }
+            ManifestFormat::Jsonl => now.format("shasum.%Y-%m-%d.%H.%M.%S.jsonl"),
+        }
+        .to_string();
+
+        // Held until copying is done, so no other run copies into output.
+        let _lock = match self.output_format {
+            OutputFormat::Directory => {
+                let (lock, stale) = lockfile::acquire(&output, &now).map_err(io::Error::other)?;
+                if let Some(stale) = stale {
+                    eprintln!(
+                        "Warning: taking over stale lock of {} ({})",
+                        output.display(),
+                        stale
+                    );
+                }
+                Some(lock)
+            }
+            OutputFormat::Tar => None,
+        };
+
+        // A passphrase unlocks the key of the copy, which encrypts files.
+        self.copy_key = match &self.key {
+            Some(key) if self.restore && input.is_dir() => {
+                Some(key.unlock(&input, false).map_err(io::Error::other)?)
+            }
+            Some(key) if self.encrypt => Some(key.unlock(&output, true).map_err(io::Error::other)?),
+            key => key.clone(),
+        };
+
+        let mut foptions = OpenOptions::new();
+        let _ = foptions.write(true);
+        let _ = foptions.create_new(true);
+
+        let mut path_shasum = output.clone();
+        path_shasum.push(date_string);
+
+        let mut shasum_file;
+        match foptions.open(&path_shasum) {
+            Ok(file) => {
+                shasum_file = file;
+            }
+            Err(e) => {
+                return Err(e);
+            }
+        }
+        info!("Writing SHA256 sums to: {}", path_shasum.display());
+        self.pending_sync.push(output.clone());
+        if self.chunk_size.is_some() {
+            let path_chunks = chunks::chunks_path(&path_shasum);
+            info!("Writing chunk hashes to: {}", path_chunks.display());
+            self.chunks_file = Some(foptions.open(&path_chunks)?);
+        }
+        if let ManifestFormat::Jsonl = self.manifest_format {
+            let header = manifest::header(&input, destination, &now);
+            self.pending_manifest.push(manifest::format_record(&header));
+        }
+
+        let mut result = match (&self.input_format, &self.output_format) {
+            (InputFormat::Tar, _) => self.extract_archive(&mut shasum_file, &input, output.clone()),
+            (InputFormat::Directory, OutputFormat::Directory) => {
+                self.copy_dir(&mut shasum_file, input.clone(), rel, output.clone())
+            }
+            (InputFormat::Directory, OutputFormat::Tar) => {
+                self.archive_dir(&mut shasum_file, input.clone(), rel)
+            }
+        };
+
+        // Whatever was successfully copied is flushed and listed,
+        // even when copying failed half-way.
+        if let Err(e) = self.sync_pending(&mut shasum_file) {
+            eprintln!("Error: fsync failed: {}", e);
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+
+        if self.tree.is_some() {
+            if let Err(e) = self.write_tree(&path_shasum) {
+                eprintln!("Error: writing tree hash: {}", e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+
+        if self.signing_key.is_some() {
+            if let Err(e) = self.sign_manifest(&path_shasum, &now) {
+                eprintln!("Error: signing {}: {}", path_shasum.display(), e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+
+        if let Some(mut archive) = self.archive.take() {
+            if let Err(e) = self.finish_archive(&mut archive, &path_shasum, &now) {
+                eprintln!("Error: finishing archive: {}", e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+
+        if !self.renames.is_empty() {
+            let mut path_renames = output;
+            path_renames.push(now.format("renames.%Y-%m-%d.%H.%M.%S.txt").to_string());
+            info!("Writing renamed names to: {}", path_renames.display());
+            if let Err(e) = self.write_renames(&input, &path_renames) {
+                eprintln!("Error: writing {}: {}", path_renames.display(), e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+
+        if interrupt::interrupted() {
+            if let Err(e) = self.write_interrupted(&path_shasum, &now) {
+                eprintln!("Error: writing interruption marker: {}", e);
+            }
+        }
 
         if self.debug {
             let debug_msg = self.debug_message();
@@ -411,7 +1378,441 @@ impl DirCopy {
             let _ = stderr.write(debug_msg.as_bytes());
         }
 
-        return result;
+        result
+    }
+
+    // Queue the manifest line of a file, in the manifest format.
+    fn queue_line(&mut self, hash: &str, rel: &std::path::Path, info: &manifest::FileInfo) {
+        let line = match self.manifest_format {
+            ManifestFormat::Sha256sum => manifest::format_line(hash, rel),
+            ManifestFormat::Bsd => manifest::format_tagged(hash, rel),
+            ManifestFormat::Jsonl => {
+                manifest::format_record(&manifest::file_record(hash, rel, info))
+            }
+        };
+        self.pending_manifest.push(line);
+        if let Some(tree) = self.tree.as_mut() {
+            tree.add(&manifest::path_bytes(rel), Algorithm::Sha256, hash);
+        }
+    }
+
+    // Queue the chunk hashes of the file last streamed, with --chunk-size.
+    fn queue_chunks(&mut self, rel: &std::path::Path) {
+        if let Some(chunk_size) = self.chunk_size {
+            let file_chunks = chunks::FileChunks {
+                chunk_size,
+                hashes: std::mem::take(&mut self.chunk_hashes),
+            };
+            self.pending_chunks
+                .push(chunks::format_record(rel, &file_chunks));
+        }
+    }
+
+    // Queue the manifest line of a copied file, and make it durable
+    // according to the fsync policy. A compressed file is listed with the
+    // hash of its data, and optionally also with its own hash.
+    fn list_file(
+        &mut self,
+        shasum_file: &mut std::fs::File,
+        hash: &str,
+        compressed: Option<&str>,
+        rel: &std::path::Path,
+        info: &manifest::FileInfo,
+        output_path: std::path::PathBuf,
+    ) -> io::Result<()> {
+        self.queue_line(hash, rel, info);
+        self.queue_chunks(rel);
+        if let Some(compressed) = compressed {
+            let stored = manifest::FileInfo::from_metadata(&fs::metadata(&output_path)?);
+            let rel = self.destination_file(rel.to_path_buf());
+            self.queue_line(compressed, &rel, &stored);
+        }
+        match self.fsync_policy {
+            FsyncPolicy::None => {
+                self.sync_pending(shasum_file)?;
+            }
+            FsyncPolicy::File => {
+                // File itself was synced by the writer thread.
+                if let Some(output) = output_path.parent() {
+                    self.pending_sync.push(output.to_path_buf());
+                }
+                self.sync_pending(shasum_file)?;
+            }
+            FsyncPolicy::Directory | FsyncPolicy::End => {
+                // Directory entry of the file, along with the file.
+                if let Some(output) = output_path.parent() {
+                    self.pending_sync.push(output.to_path_buf());
+                }
+                self.pending_sync.push(output_path);
+            }
+        }
+        Ok(())
+    }
+
+    // Like copy_dir, but from the members of a tar archive (- for stdin).
+    fn extract_archive(
+        &mut self,
+        shasum_file: &mut std::fs::File,
+        input: &std::path::Path,
+        output: std::path::PathBuf,
+    ) -> io::Result<()> {
+        let source: Box<dyn Read + Send> = if input.as_os_str() == "-" {
+            Box::new(io::stdin())
+        } else {
+            Box::new(io::BufReader::new(fs::File::open(input)?))
+        };
+        let mut reader = TarReader::new(source);
+        let mut last_dir = output.clone();
+        while let Some(member) = reader.next_member()? {
+            let lossy = String::from_utf8_lossy(&member.path).to_string();
+            let rel = match tarstream::clean_name(&member.path).map(|n| tarstream::member_path(&n))
+            {
+                Some(Ok(rel)) => rel,
+                Some(Err(e)) => {
+                    eprintln!("Warning: skipping member {}: {}", lossy, e);
+                    continue;
+                }
+                None => {
+                    eprintln!("Warning: skipping member {}: unsafe name", lossy);
+                    continue;
+                }
+            };
+            if rel.as_os_str().is_empty() {
+                continue;
+            }
+            let output_path = output.join(&rel);
+            let dir = match member.kind {
+                MemberKind::Directory => output_path.clone(),
+                _ => match output_path.parent() {
+                    Some(parent) => parent.to_path_buf(),
+                    None => output.clone(),
+                },
+            };
+            // Members of a directory are usually stored together.
+            if dir != last_dir {
+                if let FsyncPolicy::Directory = self.fsync_policy {
+                    self.pending_sync.push(last_dir.clone());
+                    self.sync_pending(shasum_file)?;
+                }
+                last_dir = dir.clone();
+            }
+            self.create_dirs(&output, &dir)?;
+            match member.kind {
+                MemberKind::Directory => (),
+                MemberKind::File => {
+                    if output_path.exists() {
+                        let old_metadata = fs::metadata(&output_path)?;
+                        if !self.overwrite_policy.do_overwrite_member(
+                            &old_metadata,
+                            member.size,
+                            member.mtime,
+                        ) {
+                            continue;
+                        }
+                    }
+                    interrupt::check()?;
+                    let s = match self.extract_file(&mut reader, member.size, &output_path) {
+                        Ok(s) => s,
+                        Err(e) => return Err(self.copy_failed(&output_path, e)),
+                    };
+                    if let Some(expected) = &member.sha256 {
+                        if *expected != s {
+                            eprintln!("Error: {} does not match hash in archive header", lossy);
+                            return Err(io::Error::from(io::ErrorKind::InvalidData));
+                        }
+                    }
+                    let info = manifest::FileInfo {
+                        size: member.size,
+                        mtime: Some(
+                            std::time::UNIX_EPOCH + std::time::Duration::from_secs(member.mtime),
+                        ),
+                        mode: Some(member.mode),
+                    };
+                    self.list_file(shasum_file, &s, None, &rel, &info, output_path)?;
+                }
+                MemberKind::Other(kind) => {
+                    eprintln!(
+                        "Warning: skipping member {}: unsupported type '{}'",
+                        lossy, kind as char
+                    );
+                }
+            }
+        }
+        if let FsyncPolicy::Directory = self.fsync_policy {
+            self.pending_sync.push(last_dir);
+            self.sync_pending(shasum_file)?;
+        }
+        Ok(())
+    }
+
+    // Create dir and missing parents, up to output.
+    fn create_dirs(&mut self, output: &std::path::Path, dir: &std::path::Path) -> io::Result<()> {
+        if dir.exists() || dir == output {
+            return Ok(());
+        }
+        if let Some(parent) = dir.parent() {
+            self.create_dirs(output, parent)?;
+            fs::create_dir(dir)?;
+            self.pending_sync.push(parent.to_path_buf());
+            self.pending_sync.push(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn extract_file<R: Read + Send>(
+        &mut self,
+        reader: &mut R,
+        len: u64,
+        output_path: &std::path::Path,
+    ) -> io::Result<String> {
+        let fo = iotools::create_write(output_path, &self.cache_options)?;
+        self.preallocate(&fo, len, output_path)?;
+        if let Some(percent) = self.parity {
+            let path = parity::parity_path(output_path);
+            self.parity_encoder = Some(parity::Encoder::create(&path, len, percent)?);
+        }
+        let (hash, _) = self.stream(reader, None, Destination::File(fo))?;
+        self.read_files += 1;
+        Ok(hash)
+    }
+
+    // Optionally append the manifest, then the end of archive marker.
+    fn finish_archive(
+        &mut self,
+        archive: &mut TarWriter,
+        path_shasum: &std::path::Path,
+        now: &DateTime<Local>,
+    ) -> io::Result<()> {
+        if self.tar_manifest_member {
+            let mut members = vec![path_shasum.to_path_buf()];
+            if self.signing_key.is_some() {
+                members.push(minisign::sig_path(path_shasum));
+            }
+            if self.tree.is_some() {
+                members.push(merkle::tree_path(path_shasum));
+            }
+            if self.chunk_size.is_some() {
+                members.push(chunks::chunks_path(path_shasum));
+            }
+            for member in members {
+                let data = fs::read(&member)?;
+                let name = member.file_name().unwrap_or_default();
+                archive.add_bytes(
+                    std::path::Path::new(name),
+                    &data,
+                    now.timestamp().max(0) as u64,
+                )?;
+            }
+        }
+        archive.finish()?;
+        if !matches!(self.fsync_policy, FsyncPolicy::None) {
+            archive.sync()?;
+        }
+        Ok(())
+    }
+
+    // Like copy_dir, but into the members of a tar archive.
+    fn archive_dir(
+        &mut self,
+        shasum_file: &mut std::fs::File,
+        input: std::path::PathBuf,
+        rel: std::path::PathBuf,
+    ) -> io::Result<()> {
+        let entries = fs::read_dir(input)?.collect::<io::Result<Vec<fs::DirEntry>>>()?;
+        let names = self.destination_names(&rel, &entries, true);
+        for (entry, name) in entries.iter().zip(names) {
+            let path = entry.path();
+            let mut rel2 = rel.clone();
+            rel2.push(&name);
+            if name != entry.file_name() {
+                self.renames.push((path.clone(), rel2.clone()));
+            }
+            if path.is_dir() {
+                let metadata = fs::metadata(&path)?;
+                if let Some(archive) = self.archive.as_mut() {
+                    archive.add_directory(&rel2, &metadata)?;
+                }
+                self.archive_dir(shasum_file, path, rel2)?;
+            } else if path.is_file() {
+                interrupt::check()?;
+                let s = self.copy_to_archive(&path, &rel2)?;
+                let info = manifest::FileInfo::from_metadata(&fs::metadata(&path)?);
+                self.queue_line(&s, &rel2, &info);
+                self.queue_chunks(&rel2);
+                match self.fsync_policy {
+                    FsyncPolicy::None | FsyncPolicy::File => {
+                        self.sync_pending(shasum_file)?;
+                    }
+                    FsyncPolicy::Directory | FsyncPolicy::End => (),
+                }
+            }
+        }
+        if let FsyncPolicy::Directory = self.fsync_policy {
+            self.sync_pending(shasum_file)?;
+        }
+        Ok(())
+    }
+
+    // Write one file as an archive member, with its SHA-256 in the pax
+    // header. On stdout the source is hashed up front, as headers cannot
+    // be patched afterwards, and must hash the same once copied.
+    fn copy_to_archive(
+        &mut self,
+        input: &std::path::Path,
+        rel: &std::path::Path,
+    ) -> io::Result<String> {
+        let cache_options = self.cache_options;
+        let seekable = match self.archive.as_ref() {
+            Some(archive) => archive.seekable(),
+            None => return Err(io::Error::other("archive not open")),
+        };
+        let fi = iotools::open_read(input, &cache_options)?;
+        let metadata = fi.metadata()?;
+        let expected = match seekable {
+            true => None,
+            false => Some(sha_path(input, self.block_size, &cache_options)?),
+        };
+        let mut archive = match self.archive.take() {
+            Some(archive) => archive,
+            None => return Err(io::Error::other("archive not open")),
+        };
+        archive.begin_file(rel, &metadata, expected.as_deref())?;
+        let (hash, destination) = self.stream(&fi, Some(&fi), Destination::Archive(archive))?;
+        let mut archive = match destination {
+            Destination::Archive(archive) => archive,
+            _ => return Err(io::Error::other("archive not open")),
+        };
+        archive.end_file(&hash)?;
+        self.archive = Some(archive);
+        if let Some(expected) = expected {
+            if expected != hash {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} changed while archiving", input.display()),
+                ));
+            }
+        }
+        self.read_files += 1;
+        Ok(hash)
+    }
+
+    // Write a detached signature of the complete shasum file beside it.
+    fn sign_manifest(
+        &self,
+        path_shasum: &std::path::Path,
+        now: &DateTime<Local>,
+    ) -> io::Result<()> {
+        let key = match &self.signing_key {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        let data = fs::read(path_shasum)?;
+        let name = path_shasum.file_name().unwrap_or_default();
+        let trusted_comment = format!(
+            "timestamp:{}\tfile:{}\thashed",
+            now.timestamp(),
+            name.to_string_lossy()
+        );
+        let path_signature = minisign::sig_path(path_shasum);
+        info!("Writing signature to: {}", path_signature.display());
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path_signature)?;
+        file.write_all(key.sign(&data, &trusted_comment).as_bytes())?;
+        if !matches!(self.fsync_policy, FsyncPolicy::None) {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // Write the tree hash of the files listed beside the shasum file.
+    fn write_tree(&self, path_shasum: &std::path::Path) -> io::Result<()> {
+        let hash = match &self.tree {
+            Some(tree) => tree.root_hash(),
+            None => return Ok(()),
+        };
+        let path_tree = merkle::tree_path(path_shasum);
+        info!("Tree hash: {}", hash);
+        info!("Writing tree hash to: {}", path_tree.display());
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path_tree)?;
+        file.write_all(merkle::format_root(&hash).as_bytes())?;
+        if !matches!(self.fsync_policy, FsyncPolicy::None) {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // Source and destination names of every renamed entry, so that
+    // renamed files can be traced back to the source.
+    fn write_renames(
+        &mut self,
+        input: &std::path::Path,
+        path_renames: &std::path::Path,
+    ) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path_renames)?;
+        for (source, destination) in &self.renames {
+            let source = source.strip_prefix(input).unwrap_or(source);
+            file.write_all(&manifest::format_rename(source, destination))?;
+        }
+        if !matches!(self.fsync_policy, FsyncPolicy::None) {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // The error of a file that failed to copy. When interrupted, what was
+    // written of the file is removed, as it is not listed.
+    fn copy_failed(&mut self, output_path: &std::path::Path, e: io::Error) -> io::Error {
+        if !interrupt::interrupted() {
+            return e;
+        }
+        for path in [output_path.to_path_buf(), parity::parity_path(output_path)] {
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    eprintln!("Error removing {}: {}", path.display(), e);
+                }
+                _ => (),
+            }
+        }
+        self.rolled_back = Some(output_path.to_path_buf());
+        io::Error::from(io::ErrorKind::Interrupted)
+    }
+
+    // Marker beside the shasum file: the run was interrupted, and the
+    // files not listed were not copied.
+    fn write_interrupted(
+        &self,
+        path_shasum: &std::path::Path,
+        started: &DateTime<Local>,
+    ) -> io::Result<()> {
+        let path_marker = interrupt::marker_path(path_shasum);
+        info!("Writing interruption marker to: {}", path_marker.display());
+        let mut text = format!(
+            "Run interrupted: {}\nStarted: {}\nFiles copied: {}\nBytes read: {}\n",
+            Local::now().to_rfc3339(),
+            started.to_rfc3339(),
+            self.read_files,
+            self.read_bytes
+        );
+        if let Some(rolled_back) = &self.rolled_back {
+            text += &format!("Rolled back: {}\n", rolled_back.display());
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path_marker)?;
+        file.write_all(text.as_bytes())?;
+        if !matches!(self.fsync_policy, FsyncPolicy::None) {
+            file.sync_all()?;
+        }
+        Ok(())
     }
 
     fn copy_dir(
@@ -421,47 +1822,604 @@ impl DirCopy {
         rel: std::path::PathBuf,
         output: std::path::PathBuf,
     ) -> io::Result<()> {
-        for entry in fs::read_dir(input)? {
-            let entry = entry?;
+        let entries = fs::read_dir(input)?.collect::<io::Result<Vec<fs::DirEntry>>>()?;
+        let names = self.destination_names(&rel, &entries, true);
+        // Small files waiting to be copied through io_uring.
+        let mut batch: Vec<SmallFile> = Vec::new();
+        for (entry, name) in entries.iter().zip(names) {
             let path = entry.path();
             let mut output_path = output.clone();
             let mut rel2 = rel.clone();
-            match path.file_name() {
-                Some(s) => {
-                    output_path.push(s);
-                    rel2.push(s);
-                }
-                None => continue, //TODO error handling
+            output_path.push(&name);
+            rel2.push(&name);
+            if name != entry.file_name() {
+                self.renames.push((path.clone(), rel2.clone()));
             }
             if path.is_dir() {
                 if !output_path.exists() {
                     fs::create_dir(output_path.clone())?;
+                    self.pending_sync.push(output.clone());
+                    self.pending_sync.push(output_path.clone());
                 }
                 self.copy_dir(shasum_file, path, rel2, output_path)?;
             } else if path.is_file() {
-                if output_path.exists() {
-                    let old_metadata = entry.metadata()?;
-                    let new_metadata = fs::metadata(output_path.clone())?;
-                    if !self
-                        .overwrite_policy
-                        .do_overwrite(&old_metadata, &new_metadata)
-                    {
-                        continue;
-                    }
+                let output_path = self.destination_file(output_path);
+                let rel2 = match self.restore {
+                    true => restored_path(&rel2),
+                    false => rel2,
+                };
+                interrupt::check()?;
+                if !self.should_copy(entry, &output_path)? {
+                    continue;
                 }
-                match self.copy(path, output_path) {
-                    Ok(s) => {
-                        let string = format!("{}  {}\n", s.to_lowercase(), rel2.display());
-                        let _ = shasum_file.write_all(string.as_bytes());
-                    }
-                    Err(_s) => {
-                        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+                let source = fs::metadata(&path)?;
+                if self.batches_files() && source.len() <= self.block_size as u64 {
+                    batch.push(SmallFile {
+                        input: path,
+                        output: output_path,
+                        rel: rel2,
+                        source,
+                    });
+                    if batch.len() >= URING_BATCH_FILES {
+                        self.copy_batch(shasum_file, std::mem::take(&mut batch))?;
                     }
+                    continue;
+                }
+                self.copy_file(shasum_file, path, output_path, &rel2, &source)?;
+            }
+        }
+        self.copy_batch(shasum_file, batch)?;
+        if let FsyncPolicy::Directory = self.fsync_policy {
+            self.pending_sync.push(output);
+            self.sync_pending(shasum_file)?;
+        }
+        Ok(())
+    }
+
+    // Copy a file, and list it in the shasum file.
+    fn copy_file(
+        &mut self,
+        shasum_file: &mut std::fs::File,
+        path: std::path::PathBuf,
+        output_path: std::path::PathBuf,
+        rel: &std::path::Path,
+        source: &fs::Metadata,
+    ) -> io::Result<()> {
+        match self.copy(path, output_path.clone()) {
+            Ok((s, compressed)) => {
+                let compressed = compressed.filter(|_| self.compressed_hashes);
+                let mut info = manifest::FileInfo::from_metadata(source);
+                // A restored file is larger than its compressed source.
+                if self.restore {
+                    info.size = fs::metadata(&output_path)?.len();
+                }
+                self.list_file(
+                    shasum_file,
+                    &s,
+                    compressed.as_deref(),
+                    rel,
+                    &info,
+                    output_path,
+                )
+            }
+            Err(_s) => {
+                let e = std::io::Error::from(std::io::ErrorKind::UnexpectedEof);
+                Err(self.copy_failed(&output_path, e))
+            }
+        }
+    }
+
+    // Whether files of at most one block are copied through io_uring
+    // several at a time, rather than one by one. Cache control needs
+    // a descriptor of each file.
+    fn batches_files(&self) -> bool {
+        self.batch_files
+            && matches!(self.io_backend, IoBackend::IoUring)
+            && matches!(self.copy_method, CopyMethod::Stream)
+            && !self.cache_options.direct_io
+            && !self.cache_options.fadvise
+            && !self.cache_options.drop_cache
+    }
+
+    // Copy small files through io_uring, and list them. Files that fail
+    // are copied again one by one, reporting errors as usual.
+    fn copy_batch(
+        &mut self,
+        shasum_file: &mut std::fs::File,
+        batch: Vec<SmallFile>,
+    ) -> io::Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.apply_tuning();
+        let tuning_start = (Instant::now(), self.read_bytes);
+        // The block size may have been tuned since the files were batched.
+        let block_size = self.block_size as u64;
+        let (batch, large): (Vec<SmallFile>, Vec<SmallFile>) = batch
+            .into_iter()
+            .partition(|file| file.source.len() <= block_size);
+        let files: Vec<(&std::path::Path, Option<&std::path::Path>, u64)> = batch
+            .iter()
+            .map(|file| {
+                let output = Some(file.output.as_path());
+                (file.input.as_path(), output, file.source.len())
+            })
+            .collect();
+        let sync = matches!(self.fsync_policy, FsyncPolicy::File);
+        let results = uring::with_ring(self.queue_size, self.block_size, |ring| {
+            let mut progress = |n: usize| {
+                self.read_bytes += n;
+                if self.emit_debug_message() {
+                    let debug_msg = self.debug_message();
+                    let mut stderr = io::stderr();
+                    let _ = stderr.write(debug_msg.as_bytes());
+                    let _ = stderr.flush();
+                }
+            };
+            uring::transfer_files(ring, &files, sync, &mut progress)
+        });
+        let results: Vec<io::Result<String>> = match results {
+            Ok(results) => {
+                self.record_tuning(tuning_start);
+                results
+            }
+            Err(e) => {
+                eprintln!(
+                    "Warning: io_uring cannot copy files several at a time ({}), copying one at a time",
+                    e
+                );
+                self.batch_files = false;
+                Vec::new()
+            }
+        };
+        let mut results = results.into_iter();
+        for file in batch.into_iter().chain(large) {
+            match results.next() {
+                Some(Ok(hash)) => {
+                    self.read_files += 1;
+                    let info = manifest::FileInfo::from_metadata(&file.source);
+                    self.list_file(shasum_file, &hash, None, &file.rel, &info, file.output)?;
+                }
+                _ => {
+                    interrupt::check()?;
+                    self.copy_file(
+                        shasum_file,
+                        file.input,
+                        file.output,
+                        &file.rel,
+                        &file.source,
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Destination names for the entries of one source directory.
+    fn destination_names(
+        &self,
+        rel: &std::path::Path,
+        entries: &[fs::DirEntry],
+        report: bool,
+    ) -> Vec<OsString> {
+        let names = self.normalized_names(rel, entries, report);
+        if self.target_profile == TargetProfile::None {
+            return names;
+        }
+        if let IncompatibleNames::Abort = self.incompatible_names {
+            return names;
+        }
+        let profile = self.target_profile;
+        let sanitized: Vec<OsString> = names.iter().map(|n| profile.sanitize(n)).collect();
+        let resolved = profile.resolve_collisions(&sanitized);
+        if report {
+            for (old, new) in names.iter().zip(resolved.iter()) {
+                if old != new {
+                    let mut rel_old = rel.to_path_buf();
+                    let mut rel_new = rel.to_path_buf();
+                    rel_old.push(old);
+                    rel_new.push(new);
+                    info!(
+                        "Renamed for target profile: {} -> {}",
+                        rel_old.display(),
+                        rel_new.display()
+                    );
+                }
+            }
+        }
+        resolved
+    }
+
+    // Names after unicode normalization, if any.
+    fn normalized_names(
+        &self,
+        rel: &std::path::Path,
+        entries: &[fs::DirEntry],
+        report: bool,
+    ) -> Vec<OsString> {
+        let mut names: Vec<OsString> = entries.iter().map(|e| e.file_name()).collect();
+        if let NameNormalization::None = self.normalize_names {
+            return names;
+        }
+        let normalized: Vec<Option<String>> = names
+            .iter()
+            .map(|name| {
+                let name = name.to_str()?;
+                let n: String = match self.normalize_names {
+                    NameNormalization::Nfd => name.nfd().collect(),
+                    _ => name.nfc().collect(),
+                };
+                if n == name {
+                    return None;
+                }
+                Some(n)
+            })
+            .collect();
+        // Both forms present in source; normalizing would merge them.
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for (name, n) in names.iter().zip(normalized.iter()) {
+            let key = match n {
+                Some(n) => n.as_str(),
+                None => match name.to_str() {
+                    Some(name) => name,
+                    None => continue,
+                },
+            };
+            *counts.entry(key).or_insert(0) += 1;
+        }
+        let mut renamed: Vec<(usize, String)> = Vec::new();
+        for i in 0..names.len() {
+            let n = match &normalized[i] {
+                Some(n) => n,
+                None => continue,
+            };
+            let mut rel_old = rel.to_path_buf();
+            rel_old.push(&names[i]);
+            if counts.get(n.as_str()).copied().unwrap_or(0) > 1 {
+                if report {
+                    eprintln!(
+                        "Warning: not normalizing {}, normalized name exists",
+                        rel_old.display()
+                    );
+                }
+                continue;
+            }
+            if report {
+                let mut rel_new = rel.to_path_buf();
+                rel_new.push(n);
+                info!(
+                    "Normalized name: {} -> {}",
+                    rel_old.display(),
+                    rel_new.display()
+                );
+            }
+            renamed.push((i, n.clone()));
+        }
+        for (i, n) in renamed {
+            names[i] = OsString::from(n);
+        }
+        names
+    }
+
+    // Destination file name of a copied file.
+    fn destination_file(&self, output_path: std::path::PathBuf) -> std::path::PathBuf {
+        if self.restore {
+            return restored_path(&output_path);
+        }
+        let mut output_path = output_path;
+        if let Compression::Zstd(_) = self.compression {
+            output_path = compress::zstd_path(&output_path);
+        }
+        if self.encrypt {
+            output_path = encrypt::age_path(&output_path);
+        }
+        output_path
+    }
+
+    fn should_copy(&self, entry: &fs::DirEntry, output_path: &std::path::Path) -> io::Result<bool> {
+        if output_path.exists() {
+            let old_metadata = entry.metadata()?;
+            let new_metadata = fs::metadata(output_path)?;
+            return Ok(self
+                .overwrite_policy
+                .do_overwrite(&old_metadata, &new_metadata));
+        }
+        Ok(true)
+    }
+
+    // Number of bytes that copy_dir would write, given the overwrite policy.
+    fn measure_dir(
+        &self,
+        input: std::path::PathBuf,
+        output: std::path::PathBuf,
+    ) -> io::Result<u64> {
+        let mut bytes: u64 = 0;
+        let entries = fs::read_dir(input)?.collect::<io::Result<Vec<fs::DirEntry>>>()?;
+        let names = self.destination_names(std::path::Path::new(""), &entries, false);
+        for (entry, name) in entries.iter().zip(names) {
+            let path = entry.path();
+            let mut output_path = output.clone();
+            output_path.push(&name);
+            if path.is_dir() {
+                bytes += self.measure_dir(path, output_path)?;
+            } else if path.is_file()
+                && (matches!(self.output_format, OutputFormat::Tar)
+                    || self.should_copy(entry, &self.destination_file(output_path))?)
+            {
+                bytes += entry.metadata()?.len();
+            }
+        }
+        Ok(bytes)
+    }
+
+    // Problems with destination names and file sizes on the target profile,
+    // one line per problem. Names are checked after any renaming.
+    fn check_compatibility(
+        &self,
+        input: std::path::PathBuf,
+        rel: std::path::PathBuf,
+        problems: &mut Vec<String>,
+    ) -> io::Result<()> {
+        let profile = self.target_profile;
+        let entries = fs::read_dir(input)?.collect::<io::Result<Vec<fs::DirEntry>>>()?;
+        let names = self.destination_names(&rel, &entries, false);
+        let mut seen: HashMap<OsString, &OsString> = HashMap::new();
+        for (entry, name) in entries.iter().zip(names.iter()) {
+            let path = entry.path();
+            let mut rel2 = rel.clone();
+            rel2.push(name);
+            for problem in profile.name_problems(name) {
+                problems.push(format!("{}: {}", rel2.display(), problem));
+            }
+            match seen.get(&profile.collision_key(name)) {
+                Some(other) => {
+                    let mut rel_other = rel.clone();
+                    rel_other.push(other);
+                    problems.push(format!(
+                        "{}: same name as {}",
+                        rel2.display(),
+                        rel_other.display()
+                    ));
+                }
+                None => {
+                    seen.insert(profile.collision_key(name), name);
+                }
+            }
+            if path.is_dir() {
+                self.check_compatibility(path, rel2, problems)?;
+            } else if path.is_file() {
+                if let Some(problem) = profile.size_problem(entry.metadata()?.len()) {
+                    problems.push(format!("{}: {}", rel2.display(), problem));
                 }
             }
         }
         Ok(())
     }
+
+    fn check_free_space(
+        &self,
+        input: &std::path::Path,
+        output: &std::path::Path,
+    ) -> io::Result<bool> {
+        let required = match self.input_format {
+            InputFormat::Directory => {
+                self.measure_dir(input.to_path_buf(), output.to_path_buf())?
+            }
+            // Upper bound; headers and padding are not copied.
+            InputFormat::Tar => fs::metadata(input)?.len(),
+        };
+        info!("Bytes to copy: {} ({})", required, size(required as f64));
+        // Kernel copies may share data blocks with the source.
+        if !matches!(self.copy_method, CopyMethod::Stream) {
+            info!("Free space: not checked, files may share data blocks with the source");
+            return Ok(true);
+        }
+        match iotools::available_space(output) {
+            Ok(available) => {
+                if required > available {
+                    eprintln!(
+                        "Insufficient free space on {}: {} required, {} available",
+                        output.display(),
+                        size(required as f64),
+                        size(available as f64)
+                    );
+                    return Ok(false);
+                }
+            }
+            Err(e) => {
+                eprintln!("Warning: unable to determine free space: {}", e);
+            }
+        }
+        Ok(true)
+    }
+
+    // Make pending files and directories durable, then list them in the manifest.
+    fn sync_pending(&mut self, shasum_file: &mut std::fs::File) -> io::Result<()> {
+        let sync = !matches!(self.fsync_policy, FsyncPolicy::None);
+        if sync {
+            if let Some(archive) = self.archive.as_mut() {
+                archive.sync()?;
+            }
+            self.pending_sync.sort();
+            self.pending_sync.dedup();
+            for path in &self.pending_sync {
+                iotools::sync_path(path)?;
+            }
+        }
+        self.pending_sync.clear();
+        for line in &self.pending_manifest {
+            shasum_file.write_all(line)?;
+        }
+        self.pending_manifest.clear();
+        if let Some(chunks_file) = self.chunks_file.as_mut() {
+            for line in &self.pending_chunks {
+                chunks_file.write_all(line)?;
+            }
+            if sync {
+                chunks_file.sync_data()?;
+            }
+        }
+        self.pending_chunks.clear();
+        if sync {
+            shasum_file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+// Write size bytes of pseudo-random data to files of up to 64 MiB in dir.
+fn write_benchmark_data(dir: &std::path::Path, size: usize) -> io::Result<()> {
+    const FILE_SIZE: usize = 64 * 1024 * 1024;
+    fs::create_dir(dir)?;
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut block = vec![0u8; 1024 * 1024];
+    let mut remaining = size;
+    let mut index = 0;
+    while remaining > 0 {
+        let mut file = fs::File::create(dir.join(format!("data.{}", index)))?;
+        let mut file_remaining = remaining.min(FILE_SIZE);
+        remaining -= file_remaining;
+        while file_remaining > 0 {
+            for word in block.chunks_mut(8) {
+                // xorshift64
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                word.copy_from_slice(&state.to_le_bytes());
+            }
+            let n = file_remaining.min(block.len());
+            file.write_all(&block[..n])?;
+            file_remaining -= n;
+        }
+        file.sync_all()?;
+        index += 1;
+    }
+    Ok(())
+}
+
+// Copy the input, or generated data, once for every benchmark setting,
+// each time into a new scratch directory removed afterwards.
+fn run_benchmark(
+    dircopy: &mut DirCopy,
+    input: &std::path::Path,
+    output: &std::path::Path,
+    block_sizes: &str,
+    queue_sizes: &str,
+    data_size: Option<String>,
+) -> io::Result<()> {
+    let block_sizes = match benchmark::parse_sizes(block_sizes) {
+        Ok(sizes) => sizes,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Ok(());
+        }
+    };
+    let queue_sizes = match benchmark::parse_sizes(queue_sizes) {
+        Ok(sizes) => sizes,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Ok(());
+        }
+    };
+    for block_size in &block_sizes {
+        if let Err(e) = dircopy.cache_options.validate(*block_size) {
+            eprintln!("{}", e);
+            return Ok(());
+        }
+    }
+    let mut modes = vec![Mode {
+        name: "threads",
+        args: "--io-backend threads",
+    }];
+    // Like in main, io_uring only streams plain copies.
+    let max_block_size = block_sizes.iter().copied().max().unwrap_or_default();
+    let max_queue_size = queue_sizes.iter().copied().max().unwrap_or_default();
+    if matches!(dircopy.copy_method, CopyMethod::Stream)
+        && matches!(dircopy.compression, Compression::None)
+        && !dircopy.encrypt
+        && !dircopy.restore
+        && dircopy.chunk_size.is_none()
+        && dircopy.parity.is_none()
+    {
+        match uring::probe(max_queue_size, max_block_size) {
+            Ok(()) => modes.push(Mode {
+                name: "io-uring",
+                args: "--io-backend io-uring",
+            }),
+            Err(e) => info!("io_uring not available, not benchmarked: {}", e),
+        }
+    }
+
+    let data_dir = match data_size {
+        Some(data_size) => {
+            // Beside the scratch directories, leaving the input untouched.
+            let dir = output.join("dircopy-benchmark-data");
+            info!("Writing benchmark data to: {}", dir.display());
+            if let Err(e) = write_benchmark_data(&dir, s2i(data_size)) {
+                eprintln!("Error writing {}: {}", dir.display(), e);
+                let _ = fs::remove_dir_all(&dir);
+                return Err(e);
+            }
+            Some(dir)
+        }
+        None => None,
+    };
+    let input = data_dir.clone().unwrap_or_else(|| input.to_path_buf());
+
+    let mut samples = Vec::new();
+    let mut result = Ok(());
+    let settings = benchmark::matrix(&block_sizes, &queue_sizes, &modes);
+    for (index, setting) in settings.into_iter().enumerate() {
+        let scratch = output.join(format!("dircopy-benchmark.{}", index));
+        if let Err(e) = fs::create_dir(&scratch) {
+            eprintln!("Error creating {}: {}", scratch.display(), e);
+            result = Err(e);
+            break;
+        }
+        dircopy.block_size = setting.block_size;
+        dircopy.queue_size = setting.queue_size;
+        dircopy.io_backend = match setting.mode.name {
+            "io-uring" => IoBackend::IoUring,
+            _ => IoBackend::Threads,
+        };
+        dircopy.pool = None;
+        dircopy.read_bytes = 0;
+        dircopy.read_files = 0;
+        dircopy.start_of_copying = Instant::now();
+        dircopy.renames.clear();
+        dircopy.pipeline = Arc::new(Pipeline::default());
+        if dircopy.tree.is_some() {
+            dircopy.tree = Some(merkle::Tree::new());
+        }
+        let sample = benchmark::measure(setting, |_| {
+            dircopy
+                .copy_directory(input.clone(), &scratch, scratch.clone())
+                .map_err(|e| e.to_string())?;
+            Ok(dircopy.read_bytes)
+        });
+        let removed = fs::remove_dir_all(&scratch);
+        match sample {
+            Ok(sample) => {
+                info!("{}: {}/s", setting.args(), size(sample.throughput()));
+                samples.push(sample);
+            }
+            Err(e) => {
+                eprintln!("Error benchmarking {}: {}", setting.args(), e);
+                result = Err(io::Error::other(e));
+                break;
+            }
+        }
+        if let Err(e) = removed {
+            eprintln!("Error removing {}: {}", scratch.display(), e);
+            result = Err(e);
+            break;
+        }
+    }
+    if let Some(dir) = data_dir {
+        fs::remove_dir_all(dir)?;
+    }
+    benchmark::print_table(&samples);
+    result
 }
 
 fn main() -> std::io::Result<()> {
@@ -469,58 +2427,494 @@ fn main() -> std::io::Result<()> {
     let queue_size = args.queue_size;
     let block_size = s2i(args.block_size);
 
-    let overwrite_policy: OverwritePolicy;
-    match args.overwrite_policy.as_str() {
-        "default" => {
-            overwrite_policy = OverwritePolicy::OverwritePolicyDefault;
+    let overwrite_policy: OverwritePolicy = match args.overwrite_policy.as_str() {
+        "default" => OverwritePolicy::Default,
+        "never" => OverwritePolicy::Never,
+        "always" => OverwritePolicy::Always,
+        _ => {
+            eprintln!("Illegal overwrite policy: {}", args.overwrite_policy);
+            return Ok(());
+        }
+    };
+
+    let fsync_policy: FsyncPolicy = match args.fsync_policy.as_str() {
+        "none" => FsyncPolicy::None,
+        "file" => FsyncPolicy::File,
+        "directory" => FsyncPolicy::Directory,
+        "end" => FsyncPolicy::End,
+        _ => {
+            eprintln!("Illegal fsync policy: {}", args.fsync_policy);
+            return Ok(());
+        }
+    };
+
+    let copy_method: CopyMethod = match args.copy_method.as_str() {
+        "stream" => CopyMethod::Stream,
+        "copy-file-range" => CopyMethod::CopyFileRange,
+        "reflink" => CopyMethod::Reflink,
+        _ => {
+            eprintln!("Illegal copy method: {}", args.copy_method);
+            return Ok(());
+        }
+    };
+
+    let fast_path_hash: FastPathHash = match args.fast_path_hash.as_str() {
+        "source" => FastPathHash::Source,
+        "destination" => FastPathHash::Destination,
+        _ => {
+            eprintln!("Illegal fast path hash: {}", args.fast_path_hash);
+            return Ok(());
         }
-        "never" => {
-            overwrite_policy = OverwritePolicy::OverwritePolicyNever;
+    };
+
+    let io_backend: IoBackend = match args.io_backend.as_str() {
+        "threads" => IoBackend::Threads,
+        "io-uring" => {
+            if let Err(e) = uring::probe(queue_size, block_size) {
+                eprintln!("io_uring not available: {}", e);
+                return Ok(());
+            }
+            IoBackend::IoUring
         }
-        "always" => {
-            overwrite_policy = OverwritePolicy::OverwritePolicyAlways;
+        _ => {
+            eprintln!("Illegal I/O backend: {}", args.io_backend);
+            return Ok(());
         }
+    };
+
+    let normalize_names: NameNormalization = match args.normalize_names.as_str() {
+        "none" => NameNormalization::None,
+        "nfc" => NameNormalization::Nfc,
+        "nfd" => NameNormalization::Nfd,
         _ => {
-            eprintln!("Illegal overwrite policy: {}", args.overwrite_policy);
+            eprintln!("Illegal name normalization: {}", args.normalize_names);
+            return Ok(());
+        }
+    };
+
+    let target_profile = match TargetProfile::parse(&args.target_profile) {
+        Some(profile) => profile,
+        None => {
+            eprintln!("Illegal target profile: {}", args.target_profile);
+            return Ok(());
+        }
+    };
+
+    let incompatible_names: IncompatibleNames = match args.incompatible_names.as_str() {
+        "abort" => IncompatibleNames::Abort,
+        "rename" => IncompatibleNames::Rename,
+        _ => {
+            eprintln!("Illegal incompatible names: {}", args.incompatible_names);
+            return Ok(());
+        }
+    };
+
+    let output_format: OutputFormat = match args.output_format.as_str() {
+        "directory" => OutputFormat::Directory,
+        "tar" => {
+            if !matches!(copy_method, CopyMethod::Stream)
+                || !matches!(io_backend, IoBackend::Threads)
+                || args.preallocate
+            {
+                eprintln!("Tar output requires copy method stream, I/O backend threads, and no preallocation");
+                return Ok(());
+            }
+            OutputFormat::Tar
+        }
+        _ => {
+            eprintln!("Illegal output format: {}", args.output_format);
+            return Ok(());
+        }
+    };
+
+    let manifest_format: ManifestFormat = match args.manifest_format.as_str() {
+        "sha256sum" => ManifestFormat::Sha256sum,
+        "bsd" => ManifestFormat::Bsd,
+        "jsonl" => ManifestFormat::Jsonl,
+        _ => {
+            eprintln!("Illegal manifest format: {}", args.manifest_format);
+            return Ok(());
+        }
+    };
+
+    let compression: Compression = match args.compress.as_str() {
+        "none" => Compression::None,
+        "zstd" => {
+            if let Err(e) = compress::valid_level(args.compression_level) {
+                eprintln!("{}", e);
+                return Ok(());
+            }
+            Compression::Zstd(args.compression_level)
+        }
+        _ => {
+            eprintln!("Illegal compression: {}", args.compress);
+            return Ok(());
+        }
+    };
+
+    let key = match (&args.passphrase_file, &args.key_file, &args.recipient[..]) {
+        (None, None, []) => None,
+        (Some(path), None, []) => Some(Key::from_passphrase_file(path)),
+        (None, Some(path), []) => Some(Key::from_key_file(path)),
+        (None, None, recipients) => Some(Key::from_recipients(recipients)),
+        _ => {
+            eprintln!("Only one of passphrase file, key file and recipients may be given");
+            return Ok(());
+        }
+    };
+    let key: Option<Key> = match key.transpose() {
+        Ok(key) => key,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Ok(());
+        }
+    };
+
+    let signing_passphrase = match &args.signing_passphrase_file {
+        Some(path) => match fs::read_to_string(path) {
+            Ok(text) => Some(text.lines().next().unwrap_or("").to_string()),
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+    let signing_key = match &args.signing_key {
+        Some(path) => match minisign::SecretKey::from_file(path, signing_passphrase.as_deref()) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                eprintln!("Signing key {}", e);
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+
+    let encrypt: bool = match args.encryption.as_str() {
+        "none" => false,
+        "age" => {
+            if key.is_none() {
+                eprintln!("Encryption requires a passphrase file, key file or recipient");
+                return Ok(());
+            }
+            true
+        }
+        _ => {
+            eprintln!("Illegal encryption: {}", args.encryption);
+            return Ok(());
+        }
+    };
+
+    if args.restore && (encrypt || !matches!(compression, Compression::None)) {
+        eprintln!("Restore cannot be combined with compression or encryption");
+        return Ok(());
+    }
+
+    let chunk_size: Option<u64> = args.chunk_size.clone().map(|s| s2i(s) as u64);
+    if chunk_size == Some(0) {
+        eprintln!("Chunk size must be greater than 0");
+        return Ok(());
+    }
+    if chunk_size.is_some()
+        && (!matches!(copy_method, CopyMethod::Stream) || !matches!(io_backend, IoBackend::Threads))
+    {
+        eprintln!("Chunk hashes require copy method stream and I/O backend threads");
+        return Ok(());
+    }
+
+    if let Some(percent) = args.parity {
+        if let Err(e) = parity::valid_percent(percent) {
+            eprintln!("{}", e);
+            return Ok(());
+        }
+        if encrypt
+            || !matches!(compression, Compression::None)
+            || !matches!(output_format, OutputFormat::Directory)
+            || !matches!(copy_method, CopyMethod::Stream)
+            || !matches!(io_backend, IoBackend::Threads)
+        {
+            eprintln!("Recovery data requires directory output, copy method stream, I/O backend threads, and no compression or encryption");
+            return Ok(());
+        }
+    }
+
+    if (args.restore || encrypt || !matches!(compression, Compression::None))
+        && (!matches!(output_format, OutputFormat::Directory)
+            || !matches!(copy_method, CopyMethod::Stream)
+            || !matches!(io_backend, IoBackend::Threads)
+            || args.preallocate
+            || args.direct_io)
+    {
+        eprintln!("Compression, encryption and restore require directory output, copy method stream, I/O backend threads, and no preallocation or direct I/O");
+        return Ok(());
+    }
+
+    let from_stdin = args.input.as_os_str() == "-";
+    let input_format: InputFormat = match args.input_format.as_str() {
+        "directory" => InputFormat::Directory,
+        "tar" => {
+            if !matches!(output_format, OutputFormat::Directory)
+                || !matches!(copy_method, CopyMethod::Stream)
+                || !matches!(io_backend, IoBackend::Threads)
+                || !matches!(normalize_names, NameNormalization::None)
+                || target_profile != TargetProfile::None
+                || !matches!(compression, Compression::None)
+                || encrypt
+                || args.restore
+            {
+                eprintln!("Tar input requires directory output, copy method stream, I/O backend threads, and no name normalization, target profile, compression, encryption or restore");
+                return Ok(());
+            }
+            InputFormat::Tar
+        }
+        _ => {
+            eprintln!("Illegal input format: {}", args.input_format);
+            return Ok(());
+        }
+    };
+
+    if args.auto_tune {
+        if args.benchmark {
+            eprintln!("Auto-tune cannot be combined with benchmark");
+            return Ok(());
+        }
+        let (max_block_size, max_queue_size) = autotune::max_setting(block_size, queue_size);
+        if let IoBackend::IoUring = io_backend {
+            if let Err(e) = uring::probe(max_queue_size, max_block_size) {
+                eprintln!("io_uring not available for auto-tune: {}", e);
+                return Ok(());
+            }
+        }
+    }
+
+    if args.benchmark
+        && (!matches!(input_format, InputFormat::Directory)
+            || !matches!(output_format, OutputFormat::Directory))
+    {
+        eprintln!("Benchmark requires directory input and output");
+        return Ok(());
+    }
+
+    // Where the shasum file is written.
+    let to_stdout = args.output.as_os_str() == "-";
+    let manifest_dir: std::path::PathBuf = match output_format {
+        OutputFormat::Directory => args.output.clone(),
+        OutputFormat::Tar => match args.output.parent() {
+            Some(parent) if !to_stdout && !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => std::path::PathBuf::from("."),
+        },
+    };
+    if let OutputFormat::Tar = output_format {
+        INFO_TO_STDERR.store(to_stdout, Ordering::Relaxed);
+        if !to_stdout && args.output.exists() {
+            eprintln!("Archive {} already exists", args.output.display());
             return Ok(());
         }
     }
 
     let mut dircopy = DirCopy {
-        queue_size: queue_size,
-        block_size: block_size,
+        queue_size,
+        block_size,
         read_bytes: 0,
         read_files: 0,
         debug: false,
         start_of_copying: Instant::now(),
         last_update: Instant::now(),
-        overwrite_policy: overwrite_policy,
+        overwrite_policy,
+        cache_options: CacheOptions {
+            direct_io: args.direct_io,
+            fadvise: args.fadvise,
+            drop_cache: args.drop_cache,
+        },
+        fsync_policy,
+        pending_sync: Vec::new(),
+        pending_manifest: Vec::new(),
+        preallocate: args.preallocate,
+        copy_method,
+        fast_path_hash,
+        fast_path_warned: false,
+        io_backend,
+        pool: None,
+        normalize_names,
+        target_profile,
+        incompatible_names,
+        renames: Vec::new(),
+        compression,
+        compressed_hashes: args.compressed_hashes,
+        key,
+        copy_key: None,
+        encrypt,
+        restore: args.restore,
+        signing_key,
+        input_format,
+        output_format,
+        archive: None,
+        tar_manifest_member: args.tar_manifest_member,
+        manifest_format,
+        tree: match args.tree_hash {
+            true => Some(merkle::Tree::new()),
+            false => None,
+        },
+        chunk_size,
+        chunk_hashes: Vec::new(),
+        pending_chunks: Vec::new(),
+        chunks_file: None,
+        parity: args.parity,
+        parity_encoder: None,
+        auto_tune: match args.auto_tune {
+            true => Some(Tuner::new(block_size, queue_size)),
+            false => None,
+        },
+        pipeline: Arc::new(Pipeline::default()),
+        rolled_back: None,
+        batch_files: true,
     };
 
-    if !args.input.is_dir() {
-        eprintln!("Directory {} is not a directory", args.input.display());
+    if let Err(e) = dircopy.cache_options.validate(block_size) {
+        eprintln!("{}", e);
         return Ok(());
     }
 
-    if !args.output.is_dir() {
-        eprintln!("Directory {} is not a directory", args.output.display());
+    match dircopy.input_format {
+        InputFormat::Directory => {
+            if !args.input.is_dir() {
+                eprintln!("Directory {} is not a directory", args.input.display());
+                return Ok(());
+            }
+        }
+        InputFormat::Tar => {
+            if !from_stdin && !args.input.is_file() {
+                eprintln!("Archive {} is not a file", args.input.display());
+                return Ok(());
+            }
+        }
+    }
+
+    if !manifest_dir.is_dir() {
+        eprintln!("Directory {} is not a directory", manifest_dir.display());
         return Ok(());
     }
-    println!("Block size: {}", block_size);
-    println!("Queue size: {}", queue_size);
-    println!("Overwite policy: {}", args.overwrite_policy);
+    info!("Block size: {}", block_size);
+    info!("Queue size: {}", queue_size);
+    info!(
+        "Buffer memory: {}",
+        size(iotools::pool_memory(queue_size, block_size) as f64)
+    );
+    info!("Overwite policy: {}", args.overwrite_policy);
+    info!("Page cache: {}", dircopy.cache_options.describe());
+    info!("Fsync policy: {}", args.fsync_policy);
+    info!("Copy method: {}", args.copy_method);
+    info!("I/O backend: {}", args.io_backend);
+    info!("Target profile: {}", args.target_profile);
+    match dircopy.compression {
+        Compression::None => info!("Compression: none"),
+        Compression::Zstd(level) => info!("Compression: zstd, level {}", level),
+    }
+    info!("Encryption: {}", args.encryption);
+    if dircopy.restore {
+        info!("Restore: decrypting .age and decompressing .zst files");
+    }
+    info!("Input format: {}", args.input_format);
+    info!("Output format: {}", args.output_format);
+    info!("Manifest format: {}", args.manifest_format);
+    if let Some(chunk_size) = dircopy.chunk_size {
+        info!("Chunk hashes: every {} bytes", chunk_size);
+    }
+    if let Some(percent) = dircopy.parity {
+        info!("Recovery data: {}%", percent);
+    }
+
+    if dircopy.auto_tune.is_some() {
+        info!("Auto-tune: block size and queue size adapted while copying");
+    }
+
+    if args.benchmark {
+        return run_benchmark(
+            &mut dircopy,
+            &args.input,
+            &args.output,
+            &args.benchmark_block_sizes,
+            &args.benchmark_queue_sizes,
+            args.benchmark_data,
+        );
+    }
+
+    if target_profile != TargetProfile::None {
+        let mut problems: Vec<String> = Vec::new();
+        dircopy.check_compatibility(
+            args.input.clone(),
+            std::path::PathBuf::new(),
+            &mut problems,
+        )?;
+        if !problems.is_empty() {
+            problems.sort();
+            for problem in &problems {
+                eprintln!("Incompatible: {}", problem);
+            }
+            eprintln!(
+                "{} problems on target profile {}, nothing copied",
+                problems.len(),
+                args.target_profile
+            );
+            return Ok(());
+        }
+    }
+
+    if !args.no_space_check
+        && !to_stdout
+        && !from_stdin
+        && !dircopy.check_free_space(&args.input, &manifest_dir)?
+    {
+        return Ok(());
+    }
+
+    if let OutputFormat::Tar = dircopy.output_format {
+        if to_stdout {
+            dircopy.archive = Some(TarWriter::stdout());
+        } else {
+            dircopy.archive = Some(TarWriter::create(&args.output)?);
+            info!("Writing archive to: {}", args.output.display());
+        }
+    }
 
     let stderr = io::stderr();
     dircopy.debug = stderr.is_terminal();
 
-    let _ = dircopy.copy_directory(args.input, args.output)?;
-    eprintln!("");
+    if let Err(e) = interrupt::install() {
+        eprintln!("Error: handling interrupts: {}", e);
+        return Ok(());
+    }
+    let result = dircopy.copy_directory(args.input, &args.output, manifest_dir);
+    if let Err(e) = result {
+        if !interrupt::interrupted() {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+    eprintln!();
     let seconds = dircopy.start_of_copying.elapsed().as_secs();
-    println!("Execution time: {}s", seconds);
-    println!(
+    info!("Execution time: {}s", seconds);
+    info!(
         "Average bandwidth: {}",
         bandwidth(dircopy.read_bytes, seconds)
     );
+    if let Some(tuner) = &dircopy.auto_tune {
+        info!("Auto-tuned: {}", tuner.describe());
+    }
+    if let Some(stages) = dircopy.pipeline.describe_stages() {
+        info!("Pipeline: {}", stages);
+    }
+    if let Some(queues) = dircopy.pipeline.describe_queues() {
+        info!("Queues: {}", queues);
+    }
+    if let Some(stage) = dircopy.pipeline.bottleneck() {
+        info!("Bottleneck: {}", stage);
+    }
+    if interrupt::interrupted() {
+        info!("Run interrupted, files not listed were not copied");
+        std::process::exit(interrupt::EXIT_INTERRUPTED);
+    }
 
     Ok(())
 }
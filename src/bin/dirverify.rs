@@ -1,22 +1,69 @@
+use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
 use std::io::BufRead;
 use std::io::BufReader;
 use std::io::Read;
-use std::path::MAIN_SEPARATOR_STR;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::PathBuf;
+use std::path::MAIN_SEPARATOR;
 use std::process::ExitCode;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::sync::mpsc::sync_channel;
+use std::sync::Arc;
+use std::sync::Mutex;
 use std::thread;
 use std::time::Instant;
 
+use chrono::Local;
 use clap::Parser;
-use sha2::{Digest, Sha256};
+use sha2::digest::DynDigest;
+use unicode_normalization::UnicodeNormalization;
 
+use autotune::Tuner;
+use benchmark::Mode;
+use chunks::ChunkHasher;
+use chunks::FileChunks;
+use encrypt::Key;
+use manifest::Algorithm;
+
+mod autotune;
+mod benchmark;
+mod chunks;
+mod compress;
+mod encrypt;
+mod interrupt;
+mod iotools;
+mod lockfile;
+mod manifest;
+mod merkle;
+mod minisign;
+mod parity;
+mod stalls;
+use iotools::pool_size;
+use iotools::AlignedBuffer;
+use iotools::BufferPool;
+use iotools::CacheOptions;
+use iotools::CacheTracker;
+use iotools::PooledBuffer;
+mod tarstream;
+use tarstream::MemberKind;
+use tarstream::TarReader;
 mod texttools;
+mod uring;
+use stalls::Pipeline;
+use stalls::Queue;
 use texttools::bandwidth;
 use texttools::s2i;
+use texttools::size;
 
-/// A directory verifier. Searches for shasum*.txt files in directories.
+/// A directory verifier. Searches for shasum*.txt and shasum*.jsonl files
+/// in directories.
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
@@ -58,10 +105,95 @@ struct Args {
     /// Print informative messages helpful for understanding processing
     #[arg(long)]
     verbose: bool,
+
+    /// Bypass the page cache with O_DIRECT reads (Linux).
+    /// Block size must be a multiple of 4K.
+    #[arg(long)]
+    direct_io: bool,
+
+    /// Advise the kernel of sequential access, and drop cached pages behind
+    /// the read position (Linux).
+    #[arg(long)]
+    fadvise: bool,
+
+    /// Drop cached pages of each file before and after verifying it,
+    /// so data is read back from disk rather than from the page cache.
+    #[arg(long)]
+    drop_cache: bool,
+
+    /// I/O backend used for reading files: threads or io-uring (Linux).
+    /// With io-uring, queue size is the number of blocks in flight.
+    #[arg(long, default_value = "threads")]
+    io_backend: String,
+
+    /// If a file is missing, look for a name differing only in unicode
+    /// normalization (NFC/NFD), and report it.
+    #[arg(long)]
+    unicode_fallback: bool,
+
+    /// Arguments are tar archives (- for stdin) rather than directories.
+    /// Members are verified without extraction, against --hash-file, or
+    /// else the SHA-256 in pax headers and shasum.*.txt members.
+    #[arg(long)]
+    tar: bool,
+
+    /// File whose first line is the passphrase of encrypted (.age) files.
+    #[arg(long)]
+    passphrase_file: Option<std::path::PathBuf>,
+
+    /// age identity file to decrypt encrypted (.age) files with.
+    #[arg(long)]
+    key_file: Option<std::path::PathBuf>,
+
+    /// minisign public key file. Shasum files must then have a valid
+    /// .minisig signature by a trusted key, or are reported as errors.
+    /// May be repeated.
+    #[arg(long)]
+    trusted_key: Vec<std::path::PathBuf>,
+
+    /// Replace failed and missing files by their copy in this reference
+    /// directory, if that matches the listed hash. Repairs are logged to
+    /// repairs.<date>.txt in the directory verified.
+    #[arg(long)]
+    repair_from: Option<std::path::PathBuf>,
+
+    /// Repair failed files from their Reed-Solomon recovery data
+    /// (<file>.parity, as written by dircopy --parity), if that restores
+    /// the listed hash. Tried before --repair-from.
+    #[arg(long)]
+    repair_parity: bool,
+
+    /// Benchmark: verify the directories once for every combination of
+    /// benchmark block sizes, queue sizes and readers (threaded sha, single
+    /// thread, io-uring), and print the throughput and CPU usage of each,
+    /// and the recommended setting.
+    #[arg(long)]
+    benchmark: bool,
+
+    /// Adapt block size and queue size while verifying: try settings within
+    /// bounds for a second of reading each, then keep the fastest.
+    /// The chosen setting is printed in the summary.
+    #[arg(long)]
+    auto_tune: bool,
+
+    /// Block sizes to benchmark, comma separated.
+    #[arg(long, default_value = "64K,128K,1M,8M")]
+    benchmark_block_sizes: String,
+
+    /// Queue sizes to benchmark, comma separated.
+    #[arg(long, default_value = "2,10,32")]
+    benchmark_queue_sizes: String,
 }
 
+// Cleared when io_uring cannot hash files several at a time, after which
+// they are hashed one at a time.
+static BATCH_FILES: AtomicBool = AtomicBool::new(true);
+
+// Most small files hashed through io_uring at a time.
+const URING_BATCH_FILES: usize = 1024;
+
 enum Message {
-    Block(Vec<u8>),
+    Block(PooledBuffer),
     Done,
     Error,
 }
@@ -72,6 +204,8 @@ struct Statistics {
     matches: usize,
     mismatches: usize,
     errors: usize,
+    normalized: usize,
+    repaired: usize,
 }
 
 impl Statistics {
@@ -82,6 +216,8 @@ impl Statistics {
             matches: 0,
             mismatches: 0,
             errors: 0,
+            normalized: 0,
+            repaired: 0,
         }
     }
     fn add(&mut self, other: &Statistics) {
@@ -90,110 +226,726 @@ impl Statistics {
         self.matches += other.matches;
         self.mismatches += other.mismatches;
         self.errors += other.errors;
+        self.normalized += other.normalized;
+        self.repaired += other.repaired;
     }
 }
 
+// A shasum file to verify an archive against, with its signature and
+// tree hash, if any.
+struct ArchiveList {
+    display: String,
+    data: Vec<u8>,
+    signature: Option<Vec<u8>>,
+    tree_hash: Option<Vec<u8>>,
+}
+
 #[derive(Clone)]
 struct DirVerify {
     // bool flags
     convert_paths: bool,
     threaded_sha_reader: bool,
+    io_uring: bool,
+    unicode_fallback: bool,
     silent: bool,
     // tuning parameters
     block_size: usize,
     queue_size: usize,
+    cache_options: CacheOptions,
+    // decrypting .age files
+    key: Option<Key>,
+    // shasum files must be signed by one of these, unless empty
+    trusted_keys: Vec<minisign_verify::PublicKey>,
+    // reference directory to repair failed files from, and log file name
+    repair_from: Option<PathBuf>,
+    repair_log: String,
+    // repairing failed files from their recovery data
+    repair_parity: bool,
+    // block and queue size for the next file, shared by all directories
+    auto_tune: Option<Arc<Mutex<Tuner>>>,
+    // time spent by reading and hashing, over all files
+    pipeline: Arc<Pipeline>,
+}
+
+// Writes to a file, hashing what is written.
+struct HashingWriter {
+    file: File,
+    hasher: Box<dyn DynDigest + Send>,
+    written: usize,
+}
+
+impl Write for HashingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.written += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
 }
 
 impl DirVerify {
-    fn parse_line(&self, line: String) -> Result<(String, String), String> {
-        if line.len() < 67 {
-            return Err(String::from("Too short"));
+    // The file listed on a line, or None for lines listing no file.
+    fn parse_line(&self, line: &[u8]) -> Result<Option<manifest::Entry>, String> {
+        // Tolerate DOS line endings.
+        let line = match line.last() {
+            Some(b'\r') => &line[..line.len() - 1],
+            _ => line,
+        };
+        // Extended manifests have a JSON record per line.
+        if line.first() == Some(&b'{') {
+            return manifest::parse_record(line);
         }
-        match &line[64..66] {
-            "  " => (),
-            _ => return Err(String::from("Expected 2 spaces")),
+        if manifest::is_tagged(line) {
+            return manifest::split_tagged(line).map(Some);
         }
-        let hash = &line[..64];
-        let filename = &line[66..];
-        let filename_corrected;
+        let (hash, name) = manifest::split_line(line)?;
+        Ok(Some(manifest::Entry {
+            algorithm: Algorithm::Sha256,
+            hash,
+            name,
+            size: None,
+        }))
+    }
 
-        if self.convert_paths {
-            if !filename.contains(MAIN_SEPARATOR_STR) {
-                match MAIN_SEPARATOR_STR {
-                    "\\" => filename_corrected = filename.replace("/", "\\"),
-                    "/" => filename_corrected = filename.replace("\\", "/"),
-                    &_ => filename_corrected = filename.to_string(),
-                }
-            } else {
-                filename_corrected = filename.to_string();
+    fn resolve_path(&self, dir: &std::path::Path, filename: Vec<u8>) -> Result<PathBuf, String> {
+        let separator = MAIN_SEPARATOR as u8;
+        let mut converted = filename.clone();
+        let exact = dir.join(manifest::path_from_bytes(filename)?);
+        if !self.convert_paths || converted.contains(&separator) {
+            return Ok(exact);
+        }
+        let foreign = if separator == b'/' { b'\\' } else { b'/' };
+        for c in converted.iter_mut() {
+            if *c == foreign {
+                *c = separator;
             }
-        } else {
-            filename_corrected = filename.to_string();
         }
-
-        Ok((hash.to_string(), filename_corrected))
+        // Names may legitimately contain backslashes on Unix.
+        if exact.exists() || encoded_file(&exact).is_some() {
+            return Ok(exact);
+        }
+        Ok(dir.join(manifest::path_from_bytes(converted)?))
     }
 
     fn verify_list(
         &self,
         stats: &mut Statistics,
-        dir: &std::path::PathBuf,
+        dir: &std::path::Path,
         list: &std::path::PathBuf,
     ) {
-        let file;
-        match File::open(&list) {
-            Ok(f) => file = f,
+        let data = match fs::read(list) {
+            Ok(data) => data,
             Err(e) => {
                 eprintln!("Error opening {}: {}", list.display(), e);
                 stats.errors += 1;
                 return;
             }
+        };
+        if !self.trusted_keys.is_empty() {
+            let signature = fs::read(minisign::sig_path(list)).ok();
+            let display = list.display().to_string();
+            if !self.signature_ok(stats, &display, &data, signature.as_deref()) {
+                return;
+            }
         }
-        let reader = BufReader::new(file);
-        for line_result in reader.lines() {
+        if !self.silent && fs::symlink_metadata(interrupt::marker_path(list)).is_ok() {
+            println!(
+                "{}: copy was interrupted, files not listed were not copied",
+                list.display()
+            );
+        }
+        // Tree hash and chunk hashes of the files listed, if written
+        // beside the list.
+        let tree_hash = fs::read(merkle::tree_path(list)).ok();
+        let file_chunks = self.read_chunks(stats, list);
+        let mut tree = merkle::Tree::new();
+        let mut failed: usize = 0;
+        // Files listed but not yet verified, in order.
+        let mut pending: Vec<(PathBuf, manifest::Entry)> = Vec::new();
+        let batch_size = match self.batches_files() {
+            true => URING_BATCH_FILES,
+            false => 1,
+        };
+        let reader = BufReader::new(&data[..]);
+        for line_result in reader.split(b'\n') {
             match line_result {
-                Ok(line) => match self.parse_line(line) {
-                    Ok((hash, filename)) => {
-                        let mut file_path = dir.clone();
-                        file_path.push(filename);
-                        self.verify_file(stats, file_path, hash);
+                Ok(line) => match self.parse_line(&line).and_then(|entry| match entry {
+                    Some(entry) => Ok(Some((self.resolve_path(dir, entry.name.clone())?, entry))),
+                    None => Ok(None),
+                }) {
+                    Ok(Some((file_path, entry))) => {
+                        pending.push((file_path, entry));
+                        if pending.len() >= batch_size {
+                            self.verify_entries(
+                                stats,
+                                dir,
+                                &mut pending,
+                                &file_chunks,
+                                &mut tree,
+                                &mut failed,
+                            );
+                        }
                     }
+                    Ok(None) => (),
                     Err(e) => {
+                        self.verify_entries(
+                            stats,
+                            dir,
+                            &mut pending,
+                            &file_chunks,
+                            &mut tree,
+                            &mut failed,
+                        );
                         eprintln!("Error: {}", e);
                         stats.errors += 1;
                         return;
                     }
                 },
                 Err(e) => {
+                    self.verify_entries(
+                        stats,
+                        dir,
+                        &mut pending,
+                        &file_chunks,
+                        &mut tree,
+                        &mut failed,
+                    );
                     eprintln!("Unexpected error processing {}: {}", list.display(), e);
                     stats.errors += 1;
                 }
             }
         }
+        self.verify_entries(
+            stats,
+            dir,
+            &mut pending,
+            &file_chunks,
+            &mut tree,
+            &mut failed,
+        );
+        if let Some(tree_hash) = tree_hash {
+            let display = list.display().to_string();
+            self.check_tree(stats, &display, &tree, failed, &tree_hash);
+        }
+    }
+
+    // Verify the files pending, hashing the small ones through io_uring
+    // several at a time first, and repair those that fail.
+    fn verify_entries(
+        &self,
+        stats: &mut Statistics,
+        dir: &std::path::Path,
+        pending: &mut Vec<(PathBuf, manifest::Entry)>,
+        file_chunks: &HashMap<Vec<u8>, FileChunks>,
+        tree: &mut merkle::Tree,
+        failed: &mut usize,
+    ) {
+        let hashes = self.sha_small_files(stats, pending);
+        for ((file_path, entry), hashed) in pending.drain(..).zip(hashes) {
+            let chunks = file_chunks.get(&entry.name);
+            let mut ok = self.verify_file(stats, file_path.clone(), &entry, chunks, hashed);
+            if !ok && (self.repair_parity || self.repair_from.is_some()) {
+                ok = self.repair_file(stats, dir, &file_path, &entry, chunks);
+            }
+            if !ok {
+                *failed += 1;
+            }
+            tree.add(&entry.name, entry.algorithm, &entry.hash);
+        }
+    }
+
+    fn batches_files(&self) -> bool {
+        self.io_uring
+            && !self.cache_options.direct_io
+            && !self.cache_options.fadvise
+            && !self.cache_options.drop_cache
+            && BATCH_FILES.load(Ordering::Relaxed)
+    }
+
+    // The SHA-256 of the listed files of at most one block, read through
+    // io_uring several at a time, or None for files to hash one at a time.
+    fn sha_small_files(
+        &self,
+        stats: &mut Statistics,
+        entries: &[(PathBuf, manifest::Entry)],
+    ) -> Vec<Option<String>> {
+        let mut hashes = vec![None; entries.len()];
+        if !self.batches_files() {
+            return hashes;
+        }
+        let tuner = self.auto_tune.as_deref();
+        let (block_size, queue_size) = match tuner {
+            Some(tuner) => lock(tuner).setting(),
+            None => (self.block_size, self.queue_size),
+        };
+        // Missing, encoded and resized files are reported one at a time.
+        let mut small: Vec<(usize, u64)> = Vec::new();
+        for (i, (file_path, entry)) in entries.iter().enumerate() {
+            let len = match fs::metadata(file_path) {
+                Ok(metadata) if metadata.is_file() => metadata.len(),
+                _ => continue,
+            };
+            if entry.algorithm == Algorithm::Sha256
+                && len <= block_size as u64
+                && entry.size.is_none_or(|size| size == len)
+            {
+                small.push((i, len));
+            }
+        }
+        if small.is_empty() {
+            return hashes;
+        }
+        let files: Vec<(&std::path::Path, Option<&std::path::Path>, u64)> = small
+            .iter()
+            .map(|&(i, len)| (entries[i].0.as_path(), None, len))
+            .collect();
+        let (start, read_bytes) = (Instant::now(), stats.read_bytes);
+        let results = uring::with_ring(queue_size, block_size, |ring| {
+            let mut progress = |n: usize| stats.read_bytes += n;
+            uring::transfer_files(ring, &files, false, &mut progress)
+        });
+        match results {
+            Ok(results) => {
+                // Files are read at one block size, so tuned as a whole.
+                if let Some(tuner) = tuner {
+                    lock(tuner).record(stats.read_bytes - read_bytes, start.elapsed());
+                }
+                for (&(i, _), result) in small.iter().zip(results) {
+                    hashes[i] = result.ok();
+                }
+            }
+            Err(e) => {
+                if BATCH_FILES.swap(false, Ordering::Relaxed) {
+                    eprintln!(
+                        "Warning: io_uring cannot hash files several at a time ({}), hashing one at a time",
+                        e
+                    );
+                }
+            }
+        }
+        hashes
+    }
+
+    // Replace a failed file by the file repaired from its recovery data,
+    // or else by a copy of its reference file, if that matches the listed
+    // hash, and log the repair.
+    fn repair_file(
+        &self,
+        stats: &mut Statistics,
+        dir: &std::path::Path,
+        file_path: &std::path::Path,
+        entry: &manifest::Entry,
+        chunks: Option<&FileChunks>,
+    ) -> bool {
+        let metadata = fs::symlink_metadata(file_path);
+        if metadata.is_err() && encoded_file(file_path).is_some() {
+            eprintln!(
+                "Error: {}: not repaired (stored compressed or encrypted)",
+                file_path.display()
+            );
+            return false;
+        }
+        let mut errors: Vec<String> = Vec::new();
+        let mut source: Option<PathBuf> = None;
+        if self.repair_parity {
+            let recovery = parity::parity_path(file_path);
+            let repaired = self.replace_verified(stats, file_path, entry, |out| {
+                let data = File::open(file_path).map_err(|e| e.to_string())?;
+                let parity =
+                    File::open(&recovery).map_err(|e| format!("{}: {}", recovery.display(), e))?;
+                parity::repair(BufReader::new(data), BufReader::new(parity), out)?;
+                Ok(())
+            });
+            match repaired {
+                Ok(()) => source = Some(recovery),
+                Err(e) => errors.push(e),
+            }
+        }
+        // With chunk hashes, only the chunks that differ are copied from
+        // the reference.
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        if let (None, Some(_), Some(chunks)) = (&source, &self.repair_from, chunks) {
+            if metadata.is_ok_and(|m| m.is_file()) {
+                if let Ok(differing) =
+                    self.differing_ranges(stats, file_path, (false, false), chunks)
+                {
+                    ranges = differing;
+                }
+            }
+        }
+        if let (None, Some(reference_dir)) = (&source, &self.repair_from) {
+            let repaired = self
+                .resolve_path(reference_dir, entry.name.clone())
+                .and_then(|reference| {
+                    self.replace_verified(stats, file_path, entry, |out| match chunks {
+                        Some(chunks) if !ranges.is_empty() => {
+                            self.copy_ranges(out, file_path, &reference, chunks, &ranges)
+                        }
+                        _ => {
+                            let mut input = File::open(&reference)
+                                .map_err(|e| format!("{}: {}", reference.display(), e))?;
+                            io::copy(&mut input, out).map_err(|e| e.to_string())?;
+                            Ok(())
+                        }
+                    })?;
+                    Ok(reference)
+                });
+            match repaired {
+                Ok(reference) => source = Some(reference),
+                Err(e) => errors.push(e),
+            }
+        }
+        let source = match source {
+            Some(source) => source,
+            None => {
+                eprintln!(
+                    "Error: {}: not repaired ({})",
+                    file_path.display(),
+                    errors.join("; ")
+                );
+                return false;
+            }
+        };
+        if !self.silent {
+            let bytes: Vec<String> = ranges
+                .iter()
+                .map(|(start, end)| format!("{}-{}", start, end))
+                .collect();
+            match bytes.is_empty() {
+                true => println!(
+                    "{}: REPAIRED from {}",
+                    file_path.display(),
+                    source.display()
+                ),
+                false => println!(
+                    "{}: REPAIRED from {} (bytes {})",
+                    file_path.display(),
+                    source.display(),
+                    bytes.join(", ")
+                ),
+            }
+        }
+        stats.repaired += 1;
+        let name = match manifest::path_from_bytes(entry.name.clone()) {
+            Ok(name) => name,
+            Err(_) => file_path.to_path_buf(),
+        };
+        let log = dir.join(&self.repair_log);
+        let logged = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log)
+            .and_then(|mut f| f.write_all(&manifest::format_rename(&source, &name)));
+        if let Err(e) = logged {
+            eprintln!("Error: writing {}: {}", log.display(), e);
+            stats.errors += 1;
+        }
+        true
+    }
+
+    // Write the data of file_path, with the byte ranges given read from
+    // reference instead. Each chunk read from reference must match its
+    // chunk hash; the whole file is checked by replace_verified.
+    fn copy_ranges(
+        &self,
+        out: &mut HashingWriter,
+        file_path: &std::path::Path,
+        reference: &std::path::Path,
+        expected: &FileChunks,
+        ranges: &[(u64, u64)],
+    ) -> Result<(), String> {
+        let mut damaged = File::open(file_path).map_err(|e| e.to_string())?;
+        let mut input =
+            File::open(reference).map_err(|e| format!("{}: {}", reference.display(), e))?;
+        let len = input.metadata().map_err(|e| e.to_string())?.len();
+        let mut buf = AlignedBuffer::new(self.block_size);
+        let mut offset: u64 = 0;
+        while offset < len {
+            let chunk_len = expected.chunk_size.min(len - offset);
+            let differs = ranges
+                .iter()
+                .any(|(start, end)| *start <= offset && offset <= *end);
+            let (file, path) = match differs {
+                true => (&mut input, reference),
+                false => (&mut damaged, file_path),
+            };
+            file.seek(SeekFrom::Start(offset))
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            let mut hasher = ChunkHasher::new(expected.chunk_size);
+            let mut left = chunk_len;
+            while left > 0 {
+                let n = left.min(self.block_size as u64) as usize;
+                match iotools::read_full(file, &mut buf[0..n]) {
+                    Ok(read) if read == n => (),
+                    Ok(_) => return Err(format!("{}: file truncated", path.display())),
+                    Err(e) => return Err(format!("{}: {}", path.display(), e)),
+                }
+                if differs {
+                    hasher.update(&buf[0..n]);
+                }
+                out.write_all(&buf[0..n]).map_err(|e| e.to_string())?;
+                left -= n as u64;
+            }
+            let index = (offset / expected.chunk_size) as usize;
+            if differs && hasher.finish().first() != expected.hashes.get(index) {
+                return Err(format!(
+                    "{}: bytes {}-{} do not match the chunk hash",
+                    reference.display(),
+                    offset,
+                    offset + chunk_len - 1
+                ));
+            }
+            offset += chunk_len;
+        }
+        Ok(())
+    }
+
+    // Write the repaired data to a temporary file beside file_path,
+    // hashing it on the way, and rename it over file_path only if the
+    // hash is listed. The repaired file keeps the mode and mtime of the
+    // failed file.
+    fn replace_verified(
+        &self,
+        stats: &mut Statistics,
+        file_path: &std::path::Path,
+        entry: &manifest::Entry,
+        fill: impl FnOnce(&mut HashingWriter) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let (parent, name) = match (file_path.parent(), file_path.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return Err(String::from("no file name")),
+        };
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(name);
+        temp_name.push(".repair");
+        let temp = parent.join(temp_name);
+        // Left behind by a repair that did not finish.
+        match fs::remove_file(&temp) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(format!("{}: {}", temp.display(), e));
+            }
+            _ => (),
+        }
+        let original = fs::metadata(file_path).ok();
+        let output = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp)
+            .map_err(|e| format!("{}: {}", temp.display(), e))?;
+        let mut writer = HashingWriter {
+            file: output,
+            hasher: entry.algorithm.hasher(),
+            written: 0,
+        };
+        let filled = fill(&mut writer);
+        stats.read_bytes += writer.written;
+        let result = match filled
+            .and_then(|_| keep_metadata(&writer.file, original.as_ref()))
+            .and_then(|_| writer.file.sync_all().map_err(|e| e.to_string()))
+        {
+            Err(e) => Err(e),
+            Ok(()) if manifest::hex_digest(writer.hasher) != entry.hash => {
+                Err(String::from("repaired data does not match the listed hash"))
+            }
+            Ok(()) => fs::rename(&temp, file_path).map_err(|e| e.to_string()),
+        };
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+            return result;
+        }
+        iotools::sync_path(parent).map_err(|e| e.to_string())
+    }
+
+    // Chunk hashes by listed name, from the chunk hash file of a list.
+    fn read_chunks(
+        &self,
+        stats: &mut Statistics,
+        list: &std::path::Path,
+    ) -> HashMap<Vec<u8>, FileChunks> {
+        let mut file_chunks: HashMap<Vec<u8>, FileChunks> = HashMap::new();
+        let path = chunks::chunks_path(list);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(_) => return file_chunks,
+        };
+        for line in data.split(|c| *c == b'\n').filter(|l| !l.is_empty()) {
+            match chunks::parse_record(line) {
+                Ok((name, chunks)) => {
+                    file_chunks.insert(name, chunks);
+                }
+                Err(e) => {
+                    eprintln!("Error: {}: {}", path.display(), e);
+                    stats.errors += 1;
+                    break;
+                }
+            }
+        }
+        file_chunks
+    }
+
+    // Compare the tree hash of the files listed with the one written by
+    // dircopy. As files are checked against their listed hashes, the
+    // tree hash holds for the files only if all of them matched.
+    fn check_tree(
+        &self,
+        stats: &mut Statistics,
+        display: &str,
+        tree: &merkle::Tree,
+        failed: usize,
+        tree_hash: &[u8],
+    ) {
+        let result = match merkle::parse_root(tree_hash) {
+            Err(e) => Err(e),
+            Ok(expected) if expected != tree.root_hash() => Err(String::from("mismatch")),
+            Ok(_) if failed != 0 => Err(format!("{} files failed", failed)),
+            Ok(expected) => Ok(expected),
+        };
+        match result {
+            Ok(hash) => {
+                if !self.silent {
+                    println!("{}: tree hash {} OK", display, hash);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error: {}: tree hash FAILED ({})", display, e);
+                stats.errors += 1;
+            }
+        }
+    }
+
+    // Whether a shasum file is signed by one of the trusted keys.
+    fn signature_ok(
+        &self,
+        stats: &mut Statistics,
+        display: &str,
+        data: &[u8],
+        signature: Option<&[u8]>,
+    ) -> bool {
+        let result = match signature.map(std::str::from_utf8) {
+            None => Err(String::from("not signed")),
+            Some(Err(_)) => Err(String::from("invalid signature file")),
+            Some(Ok(signature)) => minisign::verify(&self.trusted_keys, data, signature),
+        };
+        match result {
+            Ok(()) => {
+                if !self.silent {
+                    println!("{}: signature OK", display);
+                }
+                true
+            }
+            Err(e) => {
+                eprintln!("Error: {}: signature FAILED ({})", display, e);
+                stats.errors += 1;
+                false
+            }
+        }
     }
 
     fn verify_all_lists(
         &self,
         stats: &mut Statistics,
-        dir: &std::path::PathBuf,
+        dir: &std::path::Path,
+        sha_files: &Option<Vec<String>>,
+        sha_file: &Option<std::path::PathBuf>,
+    ) {
+        // A passphrase unlocks the key files of the copy are encrypted to.
+        let unlocked;
+        let verifier = match &self.key {
+            Some(key) => match key.unlock(dir, false) {
+                Ok(key) => {
+                    unlocked = DirVerify {
+                        key: Some(key),
+                        ..self.clone()
+                    };
+                    &unlocked
+                }
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    stats.errors += 1;
+                    return;
+                }
+            },
+            None => self,
+        };
+        verifier.verify_lists(stats, dir, sha_files, sha_file);
+    }
+
+    fn verify_lists(
+        &self,
+        stats: &mut Statistics,
+        dir: &std::path::Path,
         sha_files: &Option<Vec<String>>,
         sha_file: &Option<std::path::PathBuf>,
     ) {
         if let Some(files) = sha_files {
             for file in files {
-                let mut sha_file_pb = dir.clone();
+                let mut sha_file_pb = dir.to_path_buf();
                 sha_file_pb.push(file);
                 self.verify_list(stats, dir, &sha_file_pb);
             }
         }
         if let Some(file) = sha_file {
-            self.verify_list(stats, dir, &file);
+            self.verify_list(stats, dir, file);
         }
     }
 
-    fn verify_file(&self, stats: &mut Statistics, file_path: std::path::PathBuf, hash: String) {
+    fn verify_file(
+        &self,
+        stats: &mut Statistics,
+        file_path: std::path::PathBuf,
+        entry: &manifest::Entry,
+        chunks: Option<&FileChunks>,
+        hashed: Option<String>,
+    ) -> bool {
+        if let Some(strdigest) = hashed {
+            stats.read_files += 1;
+            return self.check_hash(
+                stats,
+                &file_path,
+                entry,
+                chunks,
+                (false, false),
+                Ok(strdigest),
+            );
+        }
+        let mut file_path = file_path;
+        if self.unicode_fallback && fs::symlink_metadata(&file_path).is_err() {
+            if let Some(normalized) = find_normalized(&file_path) {
+                if !self.silent {
+                    println!(
+                        "{}: found as {} (unicode normalization)",
+                        file_path.display(),
+                        normalized.display()
+                    );
+                }
+                stats.normalized += 1;
+                file_path = normalized;
+            }
+        }
+        // A file copied with compression and/or encryption is checked
+        // against the hash of its decoded data.
+        let mut encoding = (false, false);
+        let mut note = "";
+        if fs::symlink_metadata(&file_path).is_err() {
+            if let Some((stored, compressed, encrypted)) = encoded_file(&file_path) {
+                file_path = stored;
+                encoding = (compressed, encrypted);
+                note = encoding_note(encoding);
+            }
+        }
+        // Decoding reads through its own unaligned buffer, so encoded
+        // files are not opened for direct I/O.
+        let opened = match note.is_empty() {
+            true => iotools::open_read(&file_path, &self.cache_options),
+            false => File::open(&file_path),
+        };
         let mut file: File;
-        match File::open(&file_path) {
+        match opened {
             Ok(file_) => file = file_,
             Err(e) => {
                 eprintln!(
@@ -202,22 +954,65 @@ impl DirVerify {
                     e
                 );
                 stats.errors += 1;
-                return;
+                return false;
+            }
+        }
+        // Truncated or extended files fail without being read. The size
+        // of compressed and encrypted files differs from their data.
+        if let (Some(expected), true) = (entry.size, note.is_empty()) {
+            match file.metadata() {
+                Ok(metadata) if metadata.len() != expected => {
+                    if !self.silent {
+                        println!(
+                            "{}: FAILED (size {}, expected {})",
+                            file_path.display(),
+                            metadata.len(),
+                            expected
+                        );
+                    }
+                    stats.mismatches += 1;
+                    return false;
+                }
+                _ => (),
             }
         }
         stats.read_files += 1;
-        match self.sha_file(stats, &mut file) {
+        let algorithm = entry.algorithm;
+        let result = match note.is_empty() {
+            true => self.sha_file(stats, &mut file, algorithm),
+            false => self.sha_decoded(stats, file, encoding.0, encoding.1, algorithm),
+        };
+        self.check_hash(stats, &file_path, entry, chunks, encoding, result)
+    }
+
+    // Report whether the hash of a file read matches its listed hash.
+    fn check_hash(
+        &self,
+        stats: &mut Statistics,
+        file_path: &std::path::Path,
+        entry: &manifest::Entry,
+        chunks: Option<&FileChunks>,
+        encoding: (bool, bool),
+        result: Result<String, String>,
+    ) -> bool {
+        let note = encoding_note(encoding);
+        match result {
             Ok(strdigest) => {
-                if hash == strdigest {
+                if entry.hash == strdigest {
                     if !self.silent {
-                        println!("{}: OK", file_path.display());
+                        println!("{}: OK{}", file_path.display(), note);
                     }
                     stats.matches += 1;
+                    true
                 } else {
                     if !self.silent {
-                        println!("{}: FAILED (mismatch)", file_path.display());
+                        println!("{}: FAILED (mismatch){}", file_path.display(), note);
+                        if let Some(chunks) = chunks {
+                            self.print_ranges(stats, file_path, encoding, chunks);
+                        }
                     }
                     stats.mismatches += 1;
+                    false
                 }
             }
             Err(err) => {
@@ -225,15 +1020,179 @@ impl DirVerify {
                     println!("{}: FAILED (error: {})", file_path.display(), err);
                 }
                 stats.errors += 1;
+                false
+            }
+        }
+    }
+
+    fn print_ranges(
+        &self,
+        stats: &mut Statistics,
+        file_path: &std::path::Path,
+        encoding: (bool, bool),
+        chunks: &FileChunks,
+    ) {
+        match self.differing_ranges(stats, file_path, encoding, chunks) {
+            Ok(ranges) if ranges.is_empty() => {
+                println!(
+                    "{}: all chunks match, chunk hashes are stale",
+                    file_path.display()
+                );
+            }
+            Ok(ranges) => {
+                let ranges: Vec<String> = ranges
+                    .iter()
+                    .map(|(start, end)| format!("{}-{}", start, end))
+                    .collect();
+                println!(
+                    "{}: differing bytes {}",
+                    file_path.display(),
+                    ranges.join(", ")
+                );
+            }
+            Err(e) => {
+                println!("{}: chunks not checked ({})", file_path.display(), e);
             }
         }
     }
 
-    fn sha_file(&self, stats: &mut Statistics, file: &mut File) -> Result<String, String> {
-        if self.threaded_sha_reader {
-            self.sha_file_multithread(stats, file)
+    // Hash the data of a compressed and/or encrypted file.
+    fn sha_decoded(
+        &self,
+        stats: &mut Statistics,
+        file: File,
+        compressed: bool,
+        encrypted: bool,
+        algorithm: Algorithm,
+    ) -> Result<String, String> {
+        let mut reader = self.decoder(file, compressed, encrypted)?;
+        self.sha_reader(stats, &mut reader, None, algorithm)
+    }
+
+    // Decryption, then decompression, of a stored file.
+    fn decoder(
+        &self,
+        file: File,
+        compressed: bool,
+        encrypted: bool,
+    ) -> Result<Box<dyn Read>, String> {
+        let mut reader: Box<dyn Read> = Box::new(BufReader::new(file));
+        if encrypted {
+            let key = match &self.key {
+                Some(key) => key,
+                None => return Err(String::from("no key to decrypt with")),
+            };
+            reader = Box::new(encrypt::age_reader(reader, key).map_err(|e| e.to_string())?);
+        }
+        if compressed {
+            reader = Box::new(compress::zstd_reader(reader).map_err(|e| e.to_string())?);
+        }
+        Ok(reader)
+    }
+
+    // Byte ranges of a file differing from its chunk hashes, read again
+    // from the start. Ranges end inclusive, at the end of the data or
+    // else of the last chunk.
+    fn differing_ranges(
+        &self,
+        stats: &mut Statistics,
+        file_path: &std::path::Path,
+        encoding: (bool, bool),
+        expected: &FileChunks,
+    ) -> Result<Vec<(u64, u64)>, String> {
+        let file = File::open(file_path).map_err(|e| e.to_string())?;
+        let mut reader = self.decoder(file, encoding.0, encoding.1)?;
+        let mut hasher = ChunkHasher::new(expected.chunk_size);
+        let mut buf = AlignedBuffer::new(self.block_size);
+        let mut len: u64 = 0;
+        loop {
+            match iotools::read_full(&mut reader, &mut buf[0..self.block_size]) {
+                Ok(0) => break,
+                Ok(n) => {
+                    hasher.update(&buf[0..n]);
+                    stats.read_bytes += n;
+                    len += n as u64;
+                }
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        let actual = hasher.finish();
+        let expected_len = expected.hashes.len() as u64 * expected.chunk_size;
+        let end = len.max(expected_len);
+        Ok(chunks::differing_ranges(expected, &actual)
+            .into_iter()
+            .map(|(start, stop)| (start, stop.min(end) - 1))
+            .collect())
+    }
+
+    fn sha_file(
+        &self,
+        stats: &mut Statistics,
+        file: &mut File,
+        algorithm: Algorithm,
+    ) -> Result<String, String> {
+        let tuner = self.auto_tune.as_deref();
+        let tuned;
+        // Queue sizes are tuned between files, block sizes between blocks.
+        let mut tune_file = false;
+        let verifier = match tuner {
+            Some(tuner) => {
+                let tuner = lock(tuner);
+                let (block_size, queue_size) = tuner.setting();
+                tune_file = !tuner.tuning_blocks();
+                tuned = DirVerify {
+                    block_size,
+                    queue_size,
+                    ..self.clone()
+                };
+                &tuned
+            }
+            None => self,
+        };
+        let (start, read_bytes) = (Instant::now(), stats.read_bytes);
+        // The io_uring transfer hashes SHA-256 only.
+        let result = if verifier.io_uring && algorithm == Algorithm::Sha256 {
+            // A file is transferred at one block size, so tuned between files.
+            tune_file = true;
+            verifier.sha_file_uring(stats, file)
+        } else if verifier.threaded_sha_reader {
+            verifier.sha_file_multithread(stats, file, algorithm)
         } else {
-            self.sha_file_single_thread(stats, file)
+            verifier.sha_file_single_thread(stats, file, algorithm)
+        };
+        if let (Some(tuner), true) = (tuner, tune_file) {
+            lock(tuner).record(stats.read_bytes - read_bytes, start.elapsed());
+        }
+        result
+    }
+
+    // With --auto-tune, account a block read since the previous one to the
+    // tuner, and return the block size to read next.
+    fn tune_block(&self, block_size: usize, n: usize, since: &mut Instant) -> usize {
+        match &self.auto_tune {
+            Some(tuner) => {
+                let mut tuner = lock(tuner);
+                tuner.record_block(n, since.elapsed());
+                *since = Instant::now();
+                tuner.setting().0
+            }
+            None => block_size,
+        }
+    }
+
+    fn sha_file_uring(&self, stats: &mut Statistics, file: &mut File) -> Result<String, String> {
+        let len = match file.metadata() {
+            Ok(m) => m.len(),
+            Err(e) => return Err(e.to_string()),
+        };
+        let result = uring::with_ring(self.queue_size, self.block_size, |ring| {
+            let mut progress = |n: usize| stats.read_bytes += n;
+            uring::transfer(ring, file, None, len, false, &mut progress)
+        });
+        CacheTracker::new(&self.cache_options).finish_read(file);
+        match result {
+            Ok(strdigest) => Ok(strdigest),
+            Err(e) => Err(e.to_string()),
         }
     }
 
@@ -241,18 +1200,30 @@ impl DirVerify {
         &self,
         stats: &mut Statistics,
         file: &mut File,
+        algorithm: Algorithm,
     ) -> Result<String, String> {
         let block_size = self.block_size;
-        let mut h1 = Sha256::new();
+        let mut h1 = algorithm.hasher();
+        let mut tracker = CacheTracker::new(&self.cache_options);
 
-        let mut heap_buf: Vec<u8> = Vec::with_capacity(block_size);
-        heap_buf.resize(block_size, 0x00);
+        let mut heap_buf = AlignedBuffer::new(block_size);
+        let pipeline = &self.pipeline;
+        let mut block_size = block_size;
+        let mut t = Instant::now();
+        let mut tuned = t;
 
         loop {
-            match file.read(&mut heap_buf[0..block_size]) {
+            if heap_buf.capacity() < block_size {
+                heap_buf = AlignedBuffer::new(block_size);
+            }
+            match iotools::read_full(file, &mut heap_buf[0..block_size]) {
                 Ok(0) => break,
                 Ok(n) => {
+                    tracker.read(file, n);
+                    block_size = self.tune_block(block_size, n, &mut tuned);
+                    t = pipeline.busy_serial(stalls::Stage::Read, stalls::Stage::Hash, t);
                     h1.update(&heap_buf[0..n]);
+                    t = pipeline.busy_serial(stalls::Stage::Hash, stalls::Stage::Read, t);
                     stats.read_bytes += n;
                 }
                 Err(e) => {
@@ -260,27 +1231,31 @@ impl DirVerify {
                 }
             }
         }
-        let digest = h1.finalize();
-        let strdigest = format!("{:x}", digest);
-        Ok(strdigest)
+        tracker.finish_read(file);
+        Ok(manifest::hex_digest(h1))
     }
 
     fn sha_file_multithread(
         &self,
         stats: &mut Statistics,
         file: &mut File,
+        algorithm: Algorithm,
     ) -> Result<String, String> {
         let block_size = self.block_size;
         let queue_size = self.queue_size;
 
         let (read_tx, sha_rx) = sync_channel::<Message>(queue_size);
+        let pipeline = Arc::clone(&self.pipeline);
 
         let sha_thread = thread::spawn(move || -> Result<String, String> {
-            let mut h1 = Sha256::new();
+            let mut h1 = algorithm.hasher();
+            let mut t = Instant::now();
             loop {
-                match sha_rx.recv() {
+                match pipeline.recv(Queue::Read, &sha_rx) {
                     Ok(Message::Block(block)) => {
-                        h1.update(&block);
+                        t = pipeline.waiting(stalls::Stage::Hash, t);
+                        h1.update(&block[..]);
+                        t = pipeline.busy(stalls::Stage::Hash, t);
                     }
                     Ok(Message::Error) => {
                         return Err(String::from("T-Read: sent error"));
@@ -293,17 +1268,26 @@ impl DirVerify {
                     }
                 }
             }
-            let digest = h1.finalize();
-            let strdigest = format!("{:x}", digest);
-            return Ok(strdigest);
+            Ok(manifest::hex_digest(h1))
         });
 
-        let mut heap_buf: Vec<u8> = Vec::with_capacity(block_size);
-        heap_buf.resize(block_size, 0x00);
+        let mut tracker = CacheTracker::new(&self.cache_options);
+        let pool = BufferPool::new(pool_size(queue_size), block_size);
+        let pipeline = &self.pipeline;
+        let mut block_size = block_size;
+        let mut t = Instant::now();
+        let mut tuned = t;
 
         loop {
-            match file.read(&mut heap_buf[0..block_size]) {
+            let mut block = match pool.take() {
+                Some(block) => block,
+                None => return Err(String::from("Buffer pool closed")),
+            };
+            t = pipeline.waiting(stalls::Stage::Read, t);
+            block.resize(block_size);
+            match iotools::read_full(file, &mut block[0..block_size]) {
                 Ok(0) => {
+                    tracker.finish_read(file);
                     if let Err(e) = read_tx.send(Message::Done) {
                         return Err(format!("Error: {}", e));
                     }
@@ -311,9 +1295,14 @@ impl DirVerify {
                 }
                 Ok(n) => {
                     stats.read_bytes += n;
-                    if let Err(e) = read_tx.send(Message::Block(heap_buf[0..n].to_vec())) {
+                    tracker.read(file, n);
+                    block_size = self.tune_block(block_size, n, &mut tuned);
+                    block.truncate(n);
+                    t = pipeline.busy(stalls::Stage::Read, t);
+                    if let Err(e) = pipeline.send(Queue::Read, &read_tx, Message::Block(block)) {
                         return Err(format!("Error: {}", e));
                     }
+                    t = pipeline.waiting(stalls::Stage::Read, t);
                 }
                 Err(e) => {
                     _ = read_tx.send(Message::Error);
@@ -328,18 +1317,329 @@ impl DirVerify {
     }
 }
 
+impl DirVerify {
+    fn verify_archive(
+        &self,
+        stats: &mut Statistics,
+        archive: &std::path::Path,
+        hash_file: &Option<std::path::PathBuf>,
+    ) {
+        let source: Box<dyn Read> = if archive.as_os_str() == "-" {
+            Box::new(std::io::stdin())
+        } else {
+            match File::open(archive) {
+                Ok(f) => Box::new(BufReader::new(f)),
+                Err(e) => {
+                    eprintln!("Error opening {}: {}", archive.display(), e);
+                    stats.errors += 1;
+                    return;
+                }
+            }
+        };
+        let mut reader = TarReader::new(source);
+        // Hash of each file member, and expected hashes by member name.
+        let mut computed: HashMap<Vec<u8>, String> = HashMap::new();
+        let mut expected: Vec<(Vec<u8>, Vec<String>)> = Vec::new();
+        let mut embedded: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+        let mut signatures: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+        let mut tree_hashes: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+        loop {
+            let member = match reader.next_member() {
+                Ok(Some(member)) => member,
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("Error reading {}: {}", archive.display(), e);
+                    stats.errors += 1;
+                    break;
+                }
+            };
+            if member.kind != MemberKind::File {
+                continue;
+            }
+            let name = tarstream::clean_name(&member.path).unwrap_or(member.path);
+            let embedded_list = hash_file.is_none() && is_shasum_name(&name);
+            let signed_list = name
+                .strip_suffix(minisign::SIG_SUFFIX.as_bytes())
+                .filter(|list| hash_file.is_none() && is_shasum_name(list));
+            let tree_list = name
+                .strip_suffix(merkle::TREE_SUFFIX.as_bytes())
+                .filter(|list| hash_file.is_none() && is_shasum_name(list));
+            let mut data: Vec<u8> = Vec::new();
+            let keep = if embedded_list || signed_list.is_some() || tree_list.is_some() {
+                Some(&mut data)
+            } else {
+                None
+            };
+            stats.read_files += 1;
+            match self.sha_reader(stats, &mut reader, keep, Algorithm::Sha256) {
+                Ok(strdigest) => {
+                    computed.insert(name.clone(), strdigest);
+                }
+                Err(e) => {
+                    eprintln!("Error reading {}: {}", archive.display(), e);
+                    stats.errors += 1;
+                    break;
+                }
+            }
+            if embedded_list {
+                embedded.push((name, data));
+            } else if let Some(list) = signed_list {
+                signatures.insert(list.to_vec(), data);
+            } else if let Some(list) = tree_list {
+                tree_hashes.insert(list.to_vec(), data);
+            } else if let (Some(hash), None, true) =
+                (member.sha256, hash_file, self.trusted_keys.is_empty())
+            {
+                // Header hashes are not signed, so not trusted with trusted keys.
+                add_expected(&mut expected, name, hash);
+            }
+        }
+        // Shasum files, with their signatures and tree hashes.
+        let mut lists: Vec<ArchiveList> = Vec::new();
+        for (name, data) in embedded {
+            lists.push(ArchiveList {
+                display: format!("{}/{}", archive.display(), String::from_utf8_lossy(&name)),
+                data,
+                signature: signatures.remove(&name),
+                tree_hash: tree_hashes.remove(&name),
+            });
+        }
+        if let Some(hash_file) = hash_file {
+            match fs::read(hash_file) {
+                Ok(data) => {
+                    lists.push(ArchiveList {
+                        display: hash_file.display().to_string(),
+                        data,
+                        signature: fs::read(minisign::sig_path(hash_file)).ok(),
+                        tree_hash: fs::read(merkle::tree_path(hash_file)).ok(),
+                    });
+                }
+                Err(e) => {
+                    eprintln!("Error opening {}: {}", hash_file.display(), e);
+                    stats.errors += 1;
+                    return;
+                }
+            }
+        }
+        for list in lists {
+            let display = list.display;
+            if !self.trusted_keys.is_empty()
+                && !self.signature_ok(stats, &display, &list.data, list.signature.as_deref())
+            {
+                continue;
+            }
+            let mut tree = merkle::Tree::new();
+            let mut failed: usize = 0;
+            for line in list.data.split(|c| *c == b'\n').filter(|l| !l.is_empty()) {
+                match self.parse_line(line) {
+                    // Members are hashed once, with SHA-256.
+                    Ok(Some(entry)) if entry.algorithm != Algorithm::Sha256 => {
+                        eprintln!(
+                            "Error: {}: {} hashes are not supported in archives",
+                            display,
+                            entry.algorithm.tag()
+                        );
+                        stats.errors += 1;
+                        return;
+                    }
+                    Ok(Some(entry)) => {
+                        tree.add(&entry.name, entry.algorithm, &entry.hash);
+                        let name = self.member_name(&computed, entry.name);
+                        if computed.get(&name) != Some(&entry.hash) {
+                            failed += 1;
+                        }
+                        add_expected(&mut expected, name, entry.hash);
+                    }
+                    Ok(None) => (),
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        stats.errors += 1;
+                        return;
+                    }
+                }
+            }
+            if let Some(tree_hash) = list.tree_hash {
+                self.check_tree(stats, &display, &tree, failed, &tree_hash);
+            }
+        }
+        if expected.is_empty() {
+            eprintln!("Error: no hashes to verify {} against", archive.display());
+            stats.errors += 1;
+            return;
+        }
+        for (name, hashes) in expected {
+            let display = format!("{}/{}", archive.display(), String::from_utf8_lossy(&name));
+            match computed.get(&name) {
+                Some(strdigest) => {
+                    if hashes.iter().all(|hash| hash == strdigest) {
+                        if !self.silent {
+                            println!("{}: OK", display);
+                        }
+                        stats.matches += 1;
+                    } else {
+                        if !self.silent {
+                            println!("{}: FAILED (mismatch)", display);
+                        }
+                        stats.mismatches += 1;
+                    }
+                }
+                None => {
+                    if !self.silent {
+                        println!("{}: FAILED (missing)", display);
+                    }
+                    stats.errors += 1;
+                }
+            }
+        }
+    }
+
+    // Archive member name of a manifest file name, which may use DOS separators.
+    fn member_name(&self, computed: &HashMap<Vec<u8>, String>, filename: Vec<u8>) -> Vec<u8> {
+        let name = tarstream::clean_name(&filename).unwrap_or(filename);
+        if !self.convert_paths || computed.contains_key(&name) || name.contains(&b'/') {
+            return name;
+        }
+        let converted: Vec<u8> = name
+            .iter()
+            .map(|c| if *c == b'\\' { b'/' } else { *c })
+            .collect();
+        match tarstream::clean_name(&converted) {
+            Some(converted) if computed.contains_key(&converted) => converted,
+            _ => name,
+        }
+    }
+
+    // Hash member data, optionally keeping a copy of it.
+    fn sha_reader<R: Read>(
+        &self,
+        stats: &mut Statistics,
+        reader: &mut R,
+        mut keep: Option<&mut Vec<u8>>,
+        algorithm: Algorithm,
+    ) -> Result<String, String> {
+        let block_size = self.block_size;
+        let mut h1 = algorithm.hasher();
+        let mut heap_buf = AlignedBuffer::new(block_size);
+        let pipeline = &self.pipeline;
+        let mut t = Instant::now();
+        loop {
+            match iotools::read_full(reader, &mut heap_buf[0..block_size]) {
+                Ok(0) => break,
+                Ok(n) => {
+                    t = pipeline.busy_serial(stalls::Stage::Read, stalls::Stage::Hash, t);
+                    h1.update(&heap_buf[0..n]);
+                    t = pipeline.busy_serial(stalls::Stage::Hash, stalls::Stage::Read, t);
+                    if let Some(keep) = keep.as_mut() {
+                        keep.extend_from_slice(&heap_buf[0..n]);
+                    }
+                    stats.read_bytes += n;
+                }
+                Err(e) => {
+                    return Err(e.to_string());
+                }
+            }
+        }
+        Ok(manifest::hex_digest(h1))
+    }
+}
+
+fn add_expected(expected: &mut Vec<(Vec<u8>, Vec<String>)>, name: Vec<u8>, hash: String) {
+    match expected.iter_mut().find(|(n, _)| *n == name) {
+        Some((_, hashes)) => hashes.push(hash),
+        None => expected.push((name, vec![hash])),
+    }
+}
+
+// How a file was decoded, as noted after its result.
+fn encoding_note(encoding: (bool, bool)) -> &'static str {
+    match encoding {
+        (false, false) => "",
+        (true, false) => " (decompressed)",
+        (false, true) => " (decrypted)",
+        (true, true) => " (decrypted, decompressed)",
+    }
+}
+
+fn is_shasum_name(name: &[u8]) -> bool {
+    !name.contains(&b'/')
+        && name.starts_with(b"shasum.")
+        && (name.ends_with(b".txt") || name.ends_with(b".jsonl"))
+}
+
+// Give a repaired file the mode and mtime of the file it replaces.
+fn keep_metadata(file: &File, original: Option<&fs::Metadata>) -> Result<(), String> {
+    let original = match original {
+        Some(original) => original,
+        None => return Ok(()),
+    };
+    file.set_permissions(original.permissions())
+        .map_err(|e| e.to_string())?;
+    if let Ok(modified) = original.modified() {
+        file.set_times(fs::FileTimes::new().set_modified(modified))
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+// The stored file of a listed file copied with compression and/or
+// encryption, and whether it is compressed and encrypted.
+fn encoded_file(path: &std::path::Path) -> Option<(PathBuf, bool, bool)> {
+    for (compressed, encrypted) in [(true, false), (false, true), (true, true)] {
+        let mut stored = path.to_path_buf();
+        if compressed {
+            stored = compress::zstd_path(&stored);
+        }
+        if encrypted {
+            stored = encrypt::age_path(&stored);
+        }
+        if stored.is_file() {
+            return Some((stored, compressed, encrypted));
+        }
+    }
+    None
+}
+
+// Look up path component by component, matching names that differ only
+// in unicode normalization (NFC vs NFD), e.g. files copied from macOS.
+fn find_normalized(path: &std::path::Path) -> Option<PathBuf> {
+    let mut found = PathBuf::new();
+    for component in path.components() {
+        let mut candidate = found.clone();
+        candidate.push(component);
+        if fs::symlink_metadata(&candidate).is_ok() {
+            found = candidate;
+            continue;
+        }
+        let wanted: String = component.as_os_str().to_str()?.nfc().collect();
+        let read_dir = if found.as_os_str().is_empty() {
+            fs::read_dir(".")
+        } else {
+            fs::read_dir(&found)
+        };
+        let entry = read_dir
+            .ok()?
+            .flatten()
+            .find(|entry| match entry.file_name().to_str() {
+                Some(name) => name.nfc().eq(wanted.chars()),
+                None => false,
+            })?;
+        found.push(entry.file_name());
+    }
+    Some(found)
+}
+
 fn inspect_dir(dir: &std::path::PathBuf, detect_sha_files: bool) -> Result<Vec<String>, String> {
     if !dir.is_dir() {
         return Err(format!("Not a directory {}", dir.display()));
     }
-    let read_dir_maybe = fs::read_dir(&dir);
-    let read_dir;
-    match read_dir_maybe {
-        Ok(rd) => read_dir = rd,
+    let read_dir_maybe = fs::read_dir(dir);
+
+    let read_dir = match read_dir_maybe {
+        Ok(rd) => rd,
         Err(e) => {
             return Err(format!("{}: {}", dir.display(), e));
         }
-    }
+    };
     if !detect_sha_files {
         return Ok(Vec::new());
     }
@@ -350,9 +1650,8 @@ fn inspect_dir(dir: &std::path::PathBuf, detect_sha_files: bool) -> Result<Vec<S
             Ok(file_entry) => {
                 match file_entry.file_name().into_string() {
                     Ok(n) => name = n,
-                    Err(e) => {
-                        return Err(format!("Error reading file name: {}", e.display()));
-                    }
+                    // Not UTF-8, so cannot be a shasum file.
+                    Err(_) => continue,
                 }
                 match file_entry.file_type() {
                     Ok(file_type) => {
@@ -369,10 +1668,7 @@ fn inspect_dir(dir: &std::path::PathBuf, detect_sha_files: bool) -> Result<Vec<S
                 return Err(format!("Unexpected: {}", e));
             }
         }
-        if !name.starts_with("shasum.") {
-            continue;
-        }
-        if !name.ends_with(".txt") {
+        if !is_shasum_name(name.as_bytes()) {
             continue;
         }
         names.push(name);
@@ -413,7 +1709,7 @@ fn run_parallell(
             Ok(x) => stats.add(&x),
             Err(err) => {
                 stats.errors += 1;
-                eprintln!("{}", format!("Join error: {:?}", err));
+                eprintln!("Join error: {:?}", err);
             }
         }
     }
@@ -431,11 +1727,102 @@ fn run_sequential(
             Some(_) => None,
             None => Some(names.clone()),
         };
-        dirverify.verify_all_lists(&mut stats, &dir, &hash_names, &hash_file);
+        dirverify.verify_all_lists(&mut stats, dir, &hash_names, &hash_file);
     }
     stats
 }
 
+// Verify the directories silently once for every benchmark setting.
+fn run_benchmark(
+    dirverify: DirVerify,
+    hash_file: Option<std::path::PathBuf>,
+    sha_files: Vec<(std::path::PathBuf, Vec<String>)>,
+    parallell: bool,
+    block_sizes: &str,
+    queue_sizes: &str,
+) -> ExitCode {
+    let block_sizes = match benchmark::parse_sizes(block_sizes) {
+        Ok(sizes) => sizes,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return ExitCode::from(1);
+        }
+    };
+    let queue_sizes = match benchmark::parse_sizes(queue_sizes) {
+        Ok(sizes) => sizes,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return ExitCode::from(1);
+        }
+    };
+    for block_size in &block_sizes {
+        if let Err(e) = dirverify.cache_options.validate(*block_size) {
+            eprintln!("Error: {}", e);
+            return ExitCode::from(1);
+        }
+    }
+    let mut modes = vec![
+        Mode {
+            name: "threaded-sha",
+            args: "--io-backend threads",
+        },
+        Mode {
+            name: "single-thread",
+            args: "--no-threaded-sha",
+        },
+    ];
+    let max_block_size = block_sizes.iter().copied().max().unwrap_or_default();
+    let max_queue_size = queue_sizes.iter().copied().max().unwrap_or_default();
+    match uring::probe(max_queue_size, max_block_size) {
+        Ok(()) => modes.push(Mode {
+            name: "io-uring",
+            args: "--io-backend io-uring",
+        }),
+        Err(e) => println!("io_uring not available, not benchmarked: {}", e),
+    }
+
+    let mut samples = Vec::new();
+    let mut failed = false;
+    for setting in benchmark::matrix(&block_sizes, &queue_sizes, &modes) {
+        let mut run = dirverify.clone();
+        run.block_size = setting.block_size;
+        run.queue_size = setting.queue_size;
+        run.threaded_sha_reader = setting.mode.name != "single-thread";
+        run.io_uring = setting.mode.name == "io-uring";
+        run.silent = true;
+        let sample = benchmark::measure(setting, |_| {
+            let stats = match parallell {
+                true => run_parallell(run, hash_file.clone(), sha_files.clone()),
+                false => run_sequential(run, hash_file.clone(), sha_files.clone()),
+            };
+            failed |= stats.errors + stats.mismatches > 0;
+            Ok(stats.read_bytes)
+        });
+        match sample {
+            Ok(sample) => {
+                println!("{}: {}/s", setting.args(), size(sample.throughput()));
+                samples.push(sample);
+            }
+            Err(e) => {
+                eprintln!("Error: benchmarking {}: {}", setting.args(), e);
+                return ExitCode::from(1);
+            }
+        }
+    }
+    benchmark::print_table(&samples);
+    if failed {
+        eprintln!("Error: files failed verification while benchmarking");
+        return ExitCode::from(1);
+    }
+    ExitCode::SUCCESS
+}
+
+fn lock(tuner: &Mutex<Tuner>) -> std::sync::MutexGuard<'_, Tuner> {
+    tuner
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
 fn print_summary(stats: &Statistics, seconds: u64) {
     println!("Summary:");
     println!("* Execution time: {}s", seconds);
@@ -445,12 +1832,21 @@ fn print_summary(stats: &Statistics, seconds: u64) {
     println!("* Files matching: {}", stats.matches);
     println!("* Files mismatching: {}", stats.mismatches);
     println!("* Errors: {}", stats.errors);
+    if stats.repaired != 0 {
+        println!("* Files repaired: {}", stats.repaired);
+    }
+    if stats.normalized != 0 {
+        println!(
+            "* Names found by unicode normalization: {}",
+            stats.normalized
+        );
+    }
 }
 
 fn main() -> ExitCode {
-    let args = Args::parse();
+    let mut args = Args::parse();
 
-    if args.dir.len() == 0 {
+    if args.dir.is_empty() {
         eprintln!("Error: No directory specified");
         return ExitCode::from(1);
     }
@@ -460,13 +1856,68 @@ fn main() -> ExitCode {
         return ExitCode::from(1);
     }
 
+    if args.benchmark && args.auto_tune {
+        eprintln!("Error: cannot be both benchmarking and auto-tuning");
+        return ExitCode::from(1);
+    }
+
+    if args.benchmark && (args.tar || args.repair_from.is_some() || args.repair_parity) {
+        eprintln!("Error: cannot benchmark tar archives or repairs");
+        return ExitCode::from(1);
+    }
+
+    if args.tar && (args.repair_from.is_some() || args.repair_parity) {
+        eprintln!("Error: cannot repair tar archives");
+        return ExitCode::from(1);
+    }
+
+    let key = match (&args.passphrase_file, &args.key_file) {
+        (None, None) => None,
+        (Some(path), None) => Some(Key::from_passphrase_file(path)),
+        (None, Some(path)) => Some(Key::from_key_file(path)),
+        (Some(_), Some(_)) => {
+            eprintln!("Error: cannot have both passphrase file and key file");
+            return ExitCode::from(1);
+        }
+    };
+    let key: Option<Key> = match key.transpose() {
+        Ok(key) => key,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return ExitCode::from(1);
+        }
+    };
+
+    let mut trusted_keys: Vec<minisign_verify::PublicKey> = Vec::new();
+    for path in &args.trusted_key {
+        match minisign::public_key_from_file(path) {
+            Ok(key) => trusted_keys.push(key),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return ExitCode::from(1);
+            }
+        }
+    }
+
     let mut sha_files: Vec<(std::path::PathBuf, Vec<String>)> = Vec::new();
+    // Archives are verified as a whole, without inspecting directories.
+    let archives: Vec<std::path::PathBuf> = match args.tar {
+        true => std::mem::take(&mut args.dir),
+        false => Vec::new(),
+    };
     for dir in args.dir {
+        if let Some(holder) = lockfile::holder(&dir) {
+            eprintln!(
+                "Warning: {} is locked by a running copy ({}), files may still change",
+                dir.display(),
+                holder
+            );
+        }
         match inspect_dir(&dir, args.hash_file.is_none()) {
             Ok(names) => {
                 if args.hash_file.is_none() {
-                    if names.len() == 0 {
-                        eprintln!("Error: no shasum.*.txt files in {}", dir.display());
+                    if names.is_empty() {
+                        eprintln!("Error: no shasum files in {}", dir.display());
                         return ExitCode::from(1);
                     }
                     sha_files.push((dir, names));
@@ -483,16 +1934,63 @@ fn main() -> ExitCode {
         }
     }
 
-    let dirverify = DirVerify {
+    let block_size = s2i(args.block_size);
+    let mut dirverify = DirVerify {
         // flags
         convert_paths: !args.no_convert_paths,
         threaded_sha_reader: !args.no_threaded_sha,
+        io_uring: false,
+        unicode_fallback: args.unicode_fallback,
         silent: args.silent,
         // tuning parameters
-        block_size: s2i(args.block_size),
+        block_size,
         queue_size: args.queue_size,
+        cache_options: CacheOptions {
+            direct_io: args.direct_io,
+            fadvise: args.fadvise,
+            drop_cache: args.drop_cache,
+        },
+        key,
+        trusted_keys,
+        repair_from: args.repair_from.clone(),
+        repair_log: Local::now()
+            .format("repairs.%Y-%m-%d.%H.%M.%S.txt")
+            .to_string(),
+        repair_parity: args.repair_parity,
+        auto_tune: match args.auto_tune {
+            true => Some(Arc::new(Mutex::new(Tuner::new(
+                block_size,
+                args.queue_size,
+            )))),
+            false => None,
+        },
+        pipeline: Arc::new(Pipeline::default()),
     };
 
+    if let Err(e) = dirverify.cache_options.validate(dirverify.block_size) {
+        eprintln!("Error: {}", e);
+        return ExitCode::from(1);
+    }
+
+    match args.io_backend.as_str() {
+        "threads" => (),
+        "io-uring" => {
+            let (block_size, depth) = match args.auto_tune {
+                true => autotune::max_setting(dirverify.block_size, dirverify.queue_size),
+                false => (dirverify.block_size, dirverify.queue_size),
+            };
+            if let Err(e) = uring::probe(depth, block_size) {
+                eprintln!("Error: io_uring not available: {}", e);
+                return ExitCode::from(1);
+            }
+            dirverify.io_uring = true;
+        }
+        _ => {
+            eprintln!("Error: Illegal I/O backend: {}", args.io_backend);
+            return ExitCode::from(1);
+        }
+    }
+
     if args.verbose {
         for (dir, names) in sha_files.clone() {
             for name in names.clone() {
@@ -502,27 +2000,63 @@ fn main() -> ExitCode {
         if let Some(ref hash_file) = args.hash_file {
             println!("Utilizing specified shasum file: {}", hash_file.display());
         }
+        println!("Page cache: {}", dirverify.cache_options.describe());
+        println!("I/O backend: {}", args.io_backend);
+        if let Some(ref repair_from) = args.repair_from {
+            println!("Repairing from: {}", repair_from.display());
+        }
+        if args.repair_parity {
+            println!("Repairing from recovery data");
+        }
     }
 
-    let stats;
+    if args.benchmark {
+        return run_benchmark(
+            dirverify,
+            args.hash_file,
+            sha_files,
+            !args.no_parallell,
+            &args.benchmark_block_sizes,
+            &args.benchmark_queue_sizes,
+        );
+    }
+
+    let tuner = dirverify.auto_tune.clone();
+    let pipeline = Arc::clone(&dirverify.pipeline);
     let start = Instant::now();
 
     // ------ run the verifier ------
-    if args.no_parallell {
-        stats = run_sequential(dirverify, args.hash_file, sha_files);
+    let stats = if args.tar {
+        let mut stats = Statistics::new();
+        for archive in &archives {
+            dirverify.verify_archive(&mut stats, archive, &args.hash_file);
+        }
+        stats
+    } else if args.no_parallell {
+        run_sequential(dirverify, args.hash_file, sha_files)
     } else {
-        stats = run_parallell(dirverify, args.hash_file, sha_files);
-    }
+        run_parallell(dirverify, args.hash_file, sha_files)
+    };
     // ------
 
     let seconds = start.elapsed().as_secs();
     if !args.no_summary && !args.silent {
         print_summary(&stats, seconds);
+        if let Some(tuner) = tuner {
+            println!("* Auto-tuned: {}", lock(&tuner).describe());
+        }
+        if let Some(stages) = pipeline.describe_stages() {
+            println!("* Pipeline: {}", stages);
+        }
+        if let Some(queues) = pipeline.describe_queues() {
+            println!("* Queues: {}", queues);
+        }
+        if let Some(stage) = pipeline.bottleneck() {
+            println!("* Bottleneck: {}", stage);
+        }
     }
-    if stats.errors != 0 {
-        return ExitCode::from(1);
-    }
-    if stats.mismatches != 0 {
+    // Repaired files were counted as failed first.
+    if stats.errors + stats.mismatches > stats.repaired {
         return ExitCode::from(1);
     }
 
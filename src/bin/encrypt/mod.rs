@@ -0,0 +1,196 @@
+// age encryption of copied files, and decryption when verifying and restoring.
+// Shared between dircopy and dirverify; each binary uses a subset.
+#![allow(dead_code)]
+
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use age::secrecy::ExposeSecret;
+use age::secrecy::SecretString;
+use age::stream::StreamReader;
+use age::stream::StreamWriter;
+use age::x25519;
+
+use crate::compress::HashWriter;
+use crate::compress::Stage;
+
+// Suffix of encrypted files.
+pub const AGE_SUFFIX: &str = ".age";
+
+// Name of the encrypted file of path.
+pub fn age_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(AGE_SUFFIX);
+    PathBuf::from(name)
+}
+
+// Key file of a copy encrypted with a passphrase. Deriving a key from a
+// passphrase takes about a second, so files are encrypted to a key
+// generated for the copy, stored once, encrypted with the passphrase.
+// It is an age identity file, also read by age -d -i.
+pub const KEY_NAME: &str = "dircopy.key";
+
+#[derive(Clone)]
+pub enum Key {
+    Passphrase(SecretString),
+    // Secret keys, encrypting to their public keys, and decrypting.
+    Identities(Vec<x25519::Identity>),
+    // Public keys, encrypting only.
+    Recipients(Vec<x25519::Recipient>),
+}
+
+impl Key {
+    // The first line of the file is the passphrase.
+    pub fn from_passphrase_file(path: &Path) -> Result<Key, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let passphrase = text.lines().next().unwrap_or("");
+        if passphrase.is_empty() {
+            return Err(format!("{}: empty passphrase", path.display()));
+        }
+        Ok(Key::Passphrase(SecretString::from(passphrase)))
+    }
+
+    // An age identity file, as written by age-keygen.
+    pub fn from_key_file(path: &Path) -> Result<Key, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        parse_identities(path, &text)
+    }
+
+    // With a passphrase, the key of the copy in dir, decrypted with the
+    // passphrase, or else if create, a new key stored in dir. Without a
+    // key file, the passphrase decrypts files encrypted one by one.
+    // Other keys are used as is.
+    pub fn unlock(&self, dir: &Path, create: bool) -> Result<Key, String> {
+        let passphrase = match self {
+            Key::Passphrase(passphrase) => passphrase,
+            _ => return Ok(self.clone()),
+        };
+        let path = dir.join(KEY_NAME);
+        match fs::read(&path) {
+            Ok(data) => {
+                let mut reader = Key::Passphrase(passphrase.clone())
+                    .decrypt(&data[..])
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+                let mut text = String::new();
+                reader
+                    .read_to_string(&mut text)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+                parse_identities(&path, &text)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound && create => {
+                let identity = x25519::Identity::generate();
+                let text = format!(
+                    "# public key: {}\n{}\n",
+                    identity.to_public(),
+                    identity.to_string().expose_secret()
+                );
+                let mut data: Vec<u8> = Vec::new();
+                let mut writer = age::Encryptor::with_user_passphrase(passphrase.clone())
+                    .wrap_output(&mut data)
+                    .map_err(|e| e.to_string())?;
+                writer
+                    .write_all(text.as_bytes())
+                    .and_then(|_| writer.finish())
+                    .map_err(|e| e.to_string())?;
+                let written = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)
+                    .and_then(|mut file| {
+                        file.write_all(&data)?;
+                        file.sync_all()
+                    });
+                if let Err(e) = written {
+                    let _ = fs::remove_file(&path);
+                    return Err(format!("{}: {}", path.display(), e));
+                }
+                Ok(Key::Identities(vec![identity]))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(self.clone()),
+            Err(e) => Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    // age1... public keys.
+    pub fn from_recipients(recipients: &[String]) -> Result<Key, String> {
+        let mut parsed: Vec<x25519::Recipient> = Vec::new();
+        for recipient in recipients {
+            match recipient.parse::<x25519::Recipient>() {
+                Ok(recipient) => parsed.push(recipient),
+                Err(e) => return Err(format!("Recipient {}: {}", recipient, e)),
+            }
+        }
+        Ok(Key::Recipients(parsed))
+    }
+
+    fn encryptor(&self) -> io::Result<age::Encryptor> {
+        let public: Vec<x25519::Recipient> = match self {
+            Key::Passphrase(passphrase) => {
+                return Ok(age::Encryptor::with_user_passphrase(passphrase.clone()));
+            }
+            Key::Identities(identities) => identities.iter().map(|i| i.to_public()).collect(),
+            Key::Recipients(recipients) => recipients.clone(),
+        };
+        age::Encryptor::with_recipients(public.iter().map(|r| r as &dyn age::Recipient))
+            .map_err(io::Error::other)
+    }
+
+    fn decrypt<R: Read>(&self, reader: R) -> io::Result<StreamReader<R>> {
+        let decryptor = age::Decryptor::new(reader).map_err(io::Error::other)?;
+        let result = match self {
+            Key::Passphrase(passphrase) => {
+                let identity = age::scrypt::Identity::new(passphrase.clone());
+                decryptor.decrypt(std::iter::once(&identity as &dyn age::Identity))
+            }
+            Key::Identities(identities) => {
+                decryptor.decrypt(identities.iter().map(|i| i as &dyn age::Identity))
+            }
+            Key::Recipients(_) => {
+                return Err(io::Error::other("public keys cannot decrypt"));
+            }
+        };
+        result.map_err(io::Error::other)
+    }
+}
+
+// Secret keys of an age identity file.
+fn parse_identities(path: &Path, text: &str) -> Result<Key, String> {
+    let mut identities: Vec<x25519::Identity> = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.parse::<x25519::Identity>() {
+            Ok(identity) => identities.push(identity),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+    if identities.is_empty() {
+        return Err(format!("{}: no keys", path.display()));
+    }
+    Ok(Key::Identities(identities))
+}
+
+pub type AgeWriter = StreamWriter<Box<dyn Stage>>;
+
+impl Stage for AgeWriter {
+    fn finish_stage(self: Box<Self>) -> io::Result<HashWriter> {
+        (*self).finish()?.finish_stage()
+    }
+}
+
+pub fn age_writer(inner: Box<dyn Stage>, key: &Key) -> io::Result<AgeWriter> {
+    key.encryptor()?.wrap_output(inner)
+}
+
+pub type AgeReader<R> = StreamReader<R>;
+
+// Fails unless the key decrypts the file; the data read is authenticated.
+pub fn age_reader<R: Read>(reader: R, key: &Key) -> io::Result<AgeReader<R>> {
+    key.decrypt(reader)
+}
@@ -0,0 +1,201 @@
+// Destination file system naming rules, e.g. when copying to exFAT/NTFS
+// drives from Linux, where almost any name is allowed.
+
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TargetProfile {
+    None,
+    Exfat,
+    Ntfs,
+    Fat32,
+    // Only case-only collisions are a problem, e.g. macOS APFS/HFS+.
+    CaseInsensitive,
+}
+
+const INVALID_CHARS: &str = "\"*/:<>?\\|";
+
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// Longest name, in UTF-16 code units.
+const MAX_NAME_LENGTH: usize = 255;
+
+// Largest file FAT32 can store, 4 GiB - 1.
+const FAT32_MAX_FILE_SIZE: u64 = 0xFFFF_FFFF;
+
+impl TargetProfile {
+    pub fn parse(s: &str) -> Option<TargetProfile> {
+        match s {
+            "none" => Some(TargetProfile::None),
+            "exfat" => Some(TargetProfile::Exfat),
+            "ntfs" => Some(TargetProfile::Ntfs),
+            "fat32" => Some(TargetProfile::Fat32),
+            "case-insensitive" => Some(TargetProfile::CaseInsensitive),
+            _ => None,
+        }
+    }
+
+    // Windows naming rules apply to exFAT, NTFS and FAT32 alike.
+    fn windows_names(&self) -> bool {
+        matches!(
+            self,
+            TargetProfile::Exfat | TargetProfile::Ntfs | TargetProfile::Fat32
+        )
+    }
+
+    fn case_insensitive(&self) -> bool {
+        !matches!(self, TargetProfile::None)
+    }
+
+    // Reasons why a single name is not valid on this file system.
+    pub fn name_problems(&self, name: &OsStr) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+        if !self.windows_names() {
+            return problems;
+        }
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => {
+                problems.push(String::from("name is not valid unicode"));
+                return problems;
+            }
+        };
+        let invalid: String = name
+            .chars()
+            .filter(|c| (*c as u32) < 0x20 || INVALID_CHARS.contains(*c))
+            .collect();
+        if !invalid.is_empty() {
+            problems.push(format!("invalid characters {:?}", invalid));
+        }
+        if name.ends_with('.') || name.ends_with(' ') {
+            problems.push(String::from("trailing dot or space"));
+        }
+        if is_reserved(name) {
+            problems.push(String::from("reserved device name"));
+        }
+        if name.encode_utf16().count() > MAX_NAME_LENGTH {
+            problems.push(format!("longer than {} characters", MAX_NAME_LENGTH));
+        }
+        problems
+    }
+
+    // Problems with file contents that no renaming can fix.
+    pub fn size_problem(&self, len: u64) -> Option<String> {
+        if *self == TargetProfile::Fat32 && len > FAT32_MAX_FILE_SIZE {
+            return Some(String::from("file larger than 4 GiB - 1, FAT32 limit"));
+        }
+        None
+    }
+
+    // Key under which the file system considers two names the same.
+    pub fn collision_key(&self, name: &OsStr) -> OsString {
+        if !self.case_insensitive() {
+            return name.to_os_string();
+        }
+        match name.to_str() {
+            Some(name) => OsString::from(name.to_lowercase()),
+            None => name.to_os_string(),
+        }
+    }
+
+    // Replace what is not allowed with '_'.
+    pub fn sanitize(&self, name: &OsStr) -> OsString {
+        if !self.windows_names() {
+            return name.to_os_string();
+        }
+        let lossy = name.to_string_lossy();
+        let mut sanitized: String = lossy
+            .chars()
+            .map(|c| {
+                if (c as u32) < 0x20 || INVALID_CHARS.contains(c) {
+                    '_'
+                } else {
+                    c
+                }
+            })
+            .collect();
+        let trimmed = sanitized.trim_end_matches(['.', ' ']).len();
+        if trimmed < sanitized.len() {
+            let trailing = sanitized.len() - trimmed;
+            sanitized.truncate(trimmed);
+            sanitized.push_str(&"_".repeat(trailing));
+        }
+        if is_reserved(&sanitized) {
+            sanitized.insert(0, '_');
+        }
+        OsString::from(truncate_name(&sanitized, MAX_NAME_LENGTH))
+    }
+
+    // Destination names for one directory, deterministic regardless of
+    // directory listing order. Names colliding with an earlier name (in
+    // byte order of the original names) get a " (2)", " (3)"... suffix.
+    pub fn resolve_collisions(&self, names: &[OsString]) -> Vec<OsString> {
+        let mut order: Vec<usize> = (0..names.len()).collect();
+        order.sort_by(|a, b| names[*a].cmp(&names[*b]));
+        let mut taken: HashSet<OsString> = HashSet::new();
+        let mut result: Vec<OsString> = names.to_vec();
+        for i in order {
+            let mut candidate = names[i].clone();
+            let mut n = 2;
+            while taken.contains(&self.collision_key(&candidate)) {
+                candidate = with_suffix(&names[i], n);
+                n += 1;
+            }
+            taken.insert(self.collision_key(&candidate));
+            result[i] = candidate;
+        }
+        result
+    }
+}
+
+fn is_reserved(name: &str) -> bool {
+    let stem = match name.split_once('.') {
+        Some((stem, _)) => stem,
+        None => name,
+    };
+    let stem = stem.trim_end_matches(' ').to_uppercase();
+    RESERVED_NAMES.contains(&stem.as_str())
+}
+
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(i) if i > 0 && name.len() - i <= 16 => (&name[..i], &name[i..]),
+        _ => (name, ""),
+    }
+}
+
+// Shorten the stem, keeping the extension.
+fn truncate_name(name: &str, max: usize) -> String {
+    if name.encode_utf16().count() <= max {
+        return name.to_string();
+    }
+    let (stem, extension) = split_extension(name);
+    let budget = max.saturating_sub(extension.encode_utf16().count());
+    let mut result = String::new();
+    let mut used = 0;
+    for c in stem.chars() {
+        if used + c.len_utf16() > budget {
+            break;
+        }
+        used += c.len_utf16();
+        result.push(c);
+    }
+    result.push_str(extension);
+    result
+}
+
+fn with_suffix(name: &OsStr, n: usize) -> OsString {
+    let lossy = name.to_string_lossy();
+    let (stem, extension) = split_extension(&lossy);
+    let suffix = format!(" ({})", n);
+    let stem = truncate_name(
+        stem,
+        MAX_NAME_LENGTH - suffix.len() - extension.encode_utf16().count(),
+    );
+    OsString::from(format!("{}{}{}", stem, suffix, extension))
+}
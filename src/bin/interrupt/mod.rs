@@ -0,0 +1,49 @@
+// Graceful interruption by Ctrl-C (SIGINT) or SIGTERM: the first signal
+// stops the run at the next safe point, a second one exits at once.
+// Shared between dircopy and dirverify; each binary uses a subset.
+#![allow(dead_code)]
+
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+// Suffix of the marker written beside the shasum file of an interrupted run.
+pub const INTERRUPTED_SUFFIX: &str = ".interrupted";
+
+// Exit code after a graceful interruption, and after a second signal.
+pub const EXIT_INTERRUPTED: i32 = 3;
+pub const EXIT_ABORTED: i32 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+pub fn install() -> Result<(), String> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            eprintln!("\nAborted");
+            std::process::exit(EXIT_ABORTED);
+        }
+        eprintln!("\nInterrupted, stopping; interrupt again to abort at once");
+    })
+    .map_err(|e| e.to_string())
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+// Fails once interrupted, to stop before the next file.
+pub fn check() -> io::Result<()> {
+    match interrupted() {
+        true => Err(io::Error::from(io::ErrorKind::Interrupted)),
+        false => Ok(()),
+    }
+}
+
+// Name of the interruption marker of a shasum file.
+pub fn marker_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(INTERRUPTED_SUFFIX);
+    PathBuf::from(name)
+}
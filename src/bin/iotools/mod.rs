@@ -0,0 +1,569 @@
+// Shared between dircopy and dirverify; each binary uses a subset.
+#![allow(dead_code)]
+
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+// O_DIRECT requires buffers, offsets and lengths aligned to the logical
+// block size of the device. 4K covers both 512 byte and 4K sector drives.
+pub const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+// How often pages behind the read/write position are dropped when
+// fadvise is enabled.
+const DROP_BEHIND_INTERVAL: u64 = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheOptions {
+    // Open files with O_DIRECT, bypassing the page cache entirely.
+    pub direct_io: bool,
+    // posix_fadvise SEQUENTIAL, and DONTNEED behind the current position.
+    pub fadvise: bool,
+    // Flush files and drop their pages once done with them.
+    pub drop_cache: bool,
+}
+
+impl CacheOptions {
+    pub fn describe(&self) -> String {
+        let mut modes: Vec<&str> = Vec::new();
+        if self.direct_io {
+            modes.push("direct-io");
+        }
+        if self.fadvise {
+            modes.push("fadvise");
+        }
+        if self.drop_cache {
+            modes.push("drop-cache");
+        }
+        if modes.is_empty() {
+            return String::from("default");
+        }
+        modes.join(", ")
+    }
+
+    pub fn validate(&self, block_size: usize) -> Result<(), String> {
+        if self.direct_io && !cfg!(target_os = "linux") {
+            return Err(String::from("Direct I/O is not supported on this platform"));
+        }
+        if self.direct_io && (block_size == 0 || !block_size.is_multiple_of(DIRECT_IO_ALIGNMENT)) {
+            return Err(format!(
+                "Direct I/O requires block size to be a multiple of {}, got {}",
+                DIRECT_IO_ALIGNMENT, block_size
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Heap buffer aligned for O_DIRECT transfers.
+pub struct AlignedBuffer {
+    ptr: *mut u8,
+    len: usize,
+    capacity: usize,
+}
+
+// The buffer exclusively owns its allocation, like a Vec<u8>.
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    pub fn new(capacity: usize) -> AlignedBuffer {
+        let capacity = capacity.max(1).next_multiple_of(DIRECT_IO_ALIGNMENT);
+        let layout = Self::layout(capacity);
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        AlignedBuffer {
+            ptr,
+            len: capacity,
+            capacity,
+        }
+    }
+
+    pub fn from_slice(data: &[u8]) -> AlignedBuffer {
+        let mut buffer = AlignedBuffer::new(data.len());
+        buffer.truncate(data.len());
+        buffer.copy_from_slice(data);
+        buffer
+    }
+
+    fn layout(capacity: usize) -> Layout {
+        match Layout::from_size_align(capacity, DIRECT_IO_ALIGNMENT) {
+            Ok(layout) => layout,
+            Err(e) => panic!("Bad buffer layout: {}", e),
+        }
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.len = len.min(self.capacity);
+    }
+
+    pub fn reset(&mut self) {
+        self.len = self.capacity;
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr, Self::layout(self.capacity)) };
+    }
+}
+
+impl Clone for AlignedBuffer {
+    fn clone(&self) -> AlignedBuffer {
+        AlignedBuffer::from_slice(self)
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl AsRef<[u8]> for AlignedBuffer {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+// Number of buffers in a pool: up to queue_size blocks queued between
+// threads, plus one being read and one being consumed.
+pub fn pool_size(queue_size: usize) -> usize {
+    queue_size.max(1) + 2
+}
+
+// Upper bound of memory held by a pool.
+pub fn pool_memory(queue_size: usize, block_size: usize) -> usize {
+    pool_size(queue_size) * block_size.max(1).next_multiple_of(DIRECT_IO_ALIGNMENT)
+}
+
+/// Fixed set of buffers recycled between pipeline stages.
+/// Puts a hard ceiling on memory use; taking a buffer blocks
+/// until a consumer has returned one.
+pub struct BufferPool {
+    tx: Sender<AlignedBuffer>,
+    rx: Receiver<AlignedBuffer>,
+}
+
+impl BufferPool {
+    pub fn new(count: usize, block_size: usize) -> BufferPool {
+        let (tx, rx) = channel::<AlignedBuffer>();
+        for _ in 0..count.max(1) {
+            let _ = tx.send(AlignedBuffer::new(block_size));
+        }
+        BufferPool { tx, rx }
+    }
+
+    pub fn take(&self) -> Option<PooledBuffer> {
+        match self.rx.recv() {
+            Ok(buffer) => Some(PooledBuffer {
+                buffer: Some(buffer),
+                pool: self.tx.clone(),
+            }),
+            Err(_) => None,
+        }
+    }
+}
+
+/// Buffer on loan from a BufferPool, returned when dropped.
+pub struct PooledBuffer {
+    buffer: Option<AlignedBuffer>,
+    pool: Sender<AlignedBuffer>,
+}
+
+impl PooledBuffer {
+    pub fn truncate(&mut self, len: usize) {
+        if let Some(buffer) = self.buffer.as_mut() {
+            buffer.truncate(len);
+        }
+    }
+
+    // Replace the buffer by one of block_size, when the block size changed.
+    // The new buffer takes the place of the old one in the pool.
+    pub fn resize(&mut self, block_size: usize) {
+        let capacity = block_size.max(1).next_multiple_of(DIRECT_IO_ALIGNMENT);
+        if let Some(buffer) = self.buffer.as_mut() {
+            if buffer.capacity() != capacity {
+                *buffer = AlignedBuffer::new(block_size);
+            }
+        }
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(mut buffer) = self.buffer.take() {
+            buffer.reset();
+            // Pool may be gone already, then buffer is simply freed.
+            let _ = self.pool.send(buffer);
+        }
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match &self.buffer {
+            Some(buffer) => buffer,
+            None => &[],
+        }
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match &mut self.buffer {
+            Some(buffer) => buffer,
+            None => &mut [],
+        }
+    }
+}
+
+// Read until buffer is full or end of file.
+// Keeps file offsets aligned for O_DIRECT, as only the last read may be short.
+pub fn read_full<R: Read + ?Sized>(file: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match file.read(&mut buf[total..]) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(total)
+}
+
+// fsync a file or a directory.
+pub fn sync_path(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        return sync_dir(path);
+    }
+    OpenOptions::new().write(true).open(path)?.sync_all()
+}
+
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    File::open(path)?.sync_all()
+}
+
+// Directory entries cannot be flushed explicitly on Windows.
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+// Bytes available to unprivileged users on the file system holding path.
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let cpath = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(c) => c,
+        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+    };
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(cpath.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+// Reserve disk blocks for a file of len bytes, without changing its size.
+#[cfg(target_os = "linux")]
+pub fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    if len == 0 {
+        return Ok(());
+    }
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_KEEP_SIZE,
+            0,
+            len as libc::off_t,
+        )
+    };
+    if ret != 0 {
+        let e = io::Error::last_os_error();
+        // exFAT, some FUSE file systems and older NFS.
+        if let Some(libc::EOPNOTSUPP | libc::ENOSYS) = e.raw_os_error() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, e));
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn preallocate(_file: &File, _len: u64) -> io::Result<()> {
+    Ok(())
+}
+
+// Errors meaning a kernel copy fast path is not available for these files.
+pub fn is_unsupported(e: &io::Error) -> bool {
+    if e.kind() == io::ErrorKind::Unsupported {
+        return true;
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(errno) = e.raw_os_error() {
+        return matches!(
+            errno,
+            libc::EXDEV | libc::ENOSYS | libc::EOPNOTSUPP | libc::EINVAL | libc::ETXTBSY
+        );
+    }
+    false
+}
+
+// Make dst share data blocks with src (Btrfs, XFS, ...).
+#[cfg(target_os = "linux")]
+pub fn reflink(src: &File, dst: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    if unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn reflink(_src: &File, _dst: &File) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+// Copy len bytes from src to dst inside the kernel.
+#[cfg(target_os = "linux")]
+pub fn copy_file_range(src: &File, dst: &File, len: u64) -> io::Result<u64> {
+    use std::os::unix::io::AsRawFd;
+    let mut copied: u64 = 0;
+    loop {
+        let chunk = (len.saturating_sub(copied)).clamp(1, 1 << 30) as usize;
+        let n = unsafe {
+            libc::copy_file_range(
+                src.as_raw_fd(),
+                std::ptr::null_mut(),
+                dst.as_raw_fd(),
+                std::ptr::null_mut(),
+                chunk,
+                0,
+            )
+        };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            if copied > 0 && is_unsupported(&e) {
+                // Too late to fall back; report as a regular failure.
+                return Err(io::Error::other(e));
+            }
+            return Err(e);
+        }
+        if n == 0 {
+            break;
+        }
+        copied += n as u64;
+    }
+    Ok(copied)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn copy_file_range(_src: &File, _dst: &File, _len: u64) -> io::Result<u64> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+pub fn open_read(path: &Path, options: &CacheOptions) -> io::Result<File> {
+    let mut foptions = OpenOptions::new();
+    foptions.read(true);
+    set_direct(&mut foptions, options);
+    let file = foptions.open(path)?;
+    if options.fadvise {
+        advise(&file, 0, 0, Advice::Sequential);
+    }
+    if options.drop_cache {
+        // Evict anything already cached, so data is really read from disk.
+        advise(&file, 0, 0, Advice::DontNeed);
+    }
+    Ok(file)
+}
+
+pub fn create_write(path: &Path, options: &CacheOptions) -> io::Result<File> {
+    let mut foptions = OpenOptions::new();
+    foptions.write(true).create(true).truncate(true);
+    set_direct(&mut foptions, options);
+    let file = foptions.open(path)?;
+    if options.fadvise {
+        advise(&file, 0, 0, Advice::Sequential);
+    }
+    Ok(file)
+}
+
+#[cfg(target_os = "linux")]
+fn set_direct(foptions: &mut OpenOptions, options: &CacheOptions) {
+    use std::os::unix::fs::OpenOptionsExt;
+    if options.direct_io {
+        foptions.custom_flags(libc::O_DIRECT);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_direct(_foptions: &mut OpenOptions, _options: &CacheOptions) {}
+
+enum Advice {
+    Sequential,
+    DontNeed,
+}
+
+#[cfg(target_os = "linux")]
+fn advise(file: &File, offset: u64, len: u64, advice: Advice) {
+    use std::os::unix::io::AsRawFd;
+    let advice = match advice {
+        Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+        Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+    };
+    // Hints only, failures are harmless.
+    unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            len as libc::off_t,
+            advice,
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn advise(_file: &File, _offset: u64, _len: u64, _advice: Advice) {}
+
+// Clear O_DIRECT, so that an unaligned tail can be written.
+#[cfg(target_os = "linux")]
+pub fn clear_direct(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let fd = file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_DIRECT) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn clear_direct(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn write_out_range(file: &File, offset: u64, len: u64) {
+    use std::os::unix::io::AsRawFd;
+    unsafe {
+        libc::sync_file_range(
+            file.as_raw_fd(),
+            offset as libc::off64_t,
+            len as libc::off64_t,
+            libc::SYNC_FILE_RANGE_WAIT_BEFORE
+                | libc::SYNC_FILE_RANGE_WRITE
+                | libc::SYNC_FILE_RANGE_WAIT_AFTER,
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn write_out_range(_file: &File, _offset: u64, _len: u64) {}
+
+/// Tracks position in a file being streamed, and applies page cache policy.
+pub struct CacheTracker {
+    options: CacheOptions,
+    position: u64,
+    dropped: u64,
+    direct: bool,
+}
+
+impl CacheTracker {
+    pub fn new(options: &CacheOptions) -> CacheTracker {
+        CacheTracker {
+            options: *options,
+            position: 0,
+            dropped: 0,
+            direct: options.direct_io,
+        }
+    }
+
+    pub fn read(&mut self, file: &File, n: usize) {
+        self.position += n as u64;
+        if self.options.fadvise && self.position - self.dropped >= DROP_BEHIND_INTERVAL {
+            advise(
+                file,
+                self.dropped,
+                self.position - self.dropped,
+                Advice::DontNeed,
+            );
+            self.dropped = self.position;
+        }
+    }
+
+    pub fn write(&mut self, file: &mut File, block: &[u8]) -> io::Result<()> {
+        if self.direct && !block.len().is_multiple_of(DIRECT_IO_ALIGNMENT) {
+            // Only the final block of a file may be unaligned.
+            clear_direct(file)?;
+            self.direct = false;
+        }
+        file.write_all(block)?;
+        self.position += block.len() as u64;
+        if self.options.fadvise && self.position - self.dropped >= DROP_BEHIND_INTERVAL {
+            // Dirty pages cannot be dropped; write them out first.
+            write_out_range(file, self.dropped, self.position - self.dropped);
+            advise(
+                file,
+                self.dropped,
+                self.position - self.dropped,
+                Advice::DontNeed,
+            );
+            self.dropped = self.position;
+        }
+        Ok(())
+    }
+
+    pub fn finish_read(&mut self, file: &File) {
+        if self.options.fadvise || self.options.drop_cache {
+            advise(file, 0, 0, Advice::DontNeed);
+        }
+    }
+
+    pub fn finish_write(&mut self, file: &mut File) -> io::Result<()> {
+        if self.options.drop_cache {
+            file.flush()?;
+            file.sync_data()?;
+            advise(file, 0, 0, Advice::DontNeed);
+        } else if self.options.fadvise {
+            write_out_range(file, self.dropped, 0);
+            advise(file, self.dropped, 0, Advice::DontNeed);
+        }
+        Ok(())
+    }
+}
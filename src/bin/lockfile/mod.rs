@@ -0,0 +1,138 @@
+// Lock file in the destination root, so that two runs of dircopy never
+// copy into the same destination at once. The file is held locked by
+// the running copy, so one left behind by a crashed run is stale.
+// Shared between dircopy and dirverify; each binary uses a subset.
+#![allow(dead_code)]
+
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::fs::TryLockError;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use chrono::DateTime;
+use chrono::Local;
+
+use crate::manifest;
+
+// Name of the lock file in the destination root.
+pub const LOCK_NAME: &str = ".dircopy.lock";
+
+// Held until dropped, which removes the lock file.
+pub struct Lock {
+    path: PathBuf,
+    file: File,
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // Removed while still locked, so no other run locks it in between.
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Lock {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+pub fn lock_path(dir: &Path) -> PathBuf {
+    let mut path = dir.to_path_buf();
+    path.push(LOCK_NAME);
+    path
+}
+
+// Lock dir for a run started at started, or tell who holds the lock.
+// Also returns the holder of a stale lock that was taken over.
+pub fn acquire(dir: &Path, started: &DateTime<Local>) -> Result<(Lock, Option<String>), String> {
+    let path = lock_path(dir);
+    loop {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        match file.try_lock() {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) => {
+                return Err(format!(
+                    "{} is in use by another copy ({})",
+                    dir.display(),
+                    describe(&read_holder(&mut file))
+                ));
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(format!("{}: {}", path.display(), e));
+            }
+        }
+        // The previous holder removes the file when done; lock it anew.
+        if !same_file(&file, &path) {
+            continue;
+        }
+        let previous = read_holder(&mut file);
+        let stale = match previous.is_empty() {
+            true => None,
+            false => Some(describe(&previous)),
+        };
+        let holder = format!(
+            "pid: {}\nhost: {}\nstarted: {}\n",
+            std::process::id(),
+            manifest::hostname(),
+            started.to_rfc3339()
+        );
+        file.set_len(0)
+            .and_then(|_| file.write_all(holder.as_bytes()))
+            .and_then(|_| file.sync_all())
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        return Ok((Lock { path, file }, stale));
+    }
+}
+
+// Who holds the lock of dir, if a copy into it is running.
+pub fn holder(dir: &Path) -> Option<String> {
+    let mut file = File::open(lock_path(dir)).ok()?;
+    match file.try_lock_shared() {
+        Err(TryLockError::WouldBlock) => Some(describe(&read_holder(&mut file))),
+        _ => None,
+    }
+}
+
+fn read_holder(file: &mut File) -> String {
+    let mut text = String::new();
+    let _ = file.read_to_string(&mut text);
+    text
+}
+
+// "pid: 1\nhost: h\n..." as "pid 1, host h, ...".
+fn describe(holder: &str) -> String {
+    let fields: Vec<String> = holder
+        .lines()
+        .filter_map(|line| line.split_once(": "))
+        .map(|(key, value)| format!("{} {}", key, value))
+        .collect();
+    match fields.is_empty() {
+        true => String::from("unknown holder"),
+        false => fields.join(", "),
+    }
+}
+
+#[cfg(unix)]
+fn same_file(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+// Without inode numbers, trust the file opened.
+#[cfg(not(unix))]
+fn same_file(_file: &File, _path: &Path) -> bool {
+    true
+}
@@ -0,0 +1,460 @@
+// Reading and writing of shasum*.txt lines, GNU or BSD tagged, and of
+// extended shasum*.jsonl records.
+// Shared between dircopy and dirverify; each binary uses a subset.
+#![allow(dead_code)]
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::DateTime;
+use chrono::Local;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::digest::DynDigest;
+use sha2::Digest;
+
+// Hash algorithms of BSD tagged lines and extended records.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Algorithm {
+    Sha1,
+    Sha224,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl Algorithm {
+    // As in "SHA256 (name) = hash", in any case.
+    pub fn from_tag(tag: &[u8]) -> Option<Algorithm> {
+        match tag.to_ascii_uppercase().as_slice() {
+            b"SHA1" => Some(Algorithm::Sha1),
+            b"SHA224" => Some(Algorithm::Sha224),
+            b"SHA256" => Some(Algorithm::Sha256),
+            b"SHA384" => Some(Algorithm::Sha384),
+            b"SHA512" => Some(Algorithm::Sha512),
+            _ => None,
+        }
+    }
+
+    pub fn tag(&self) -> &'static str {
+        match self {
+            Algorithm::Sha1 => "SHA1",
+            Algorithm::Sha224 => "SHA224",
+            Algorithm::Sha256 => "SHA256",
+            Algorithm::Sha384 => "SHA384",
+            Algorithm::Sha512 => "SHA512",
+        }
+    }
+
+    // Length of the hash in hexadecimal.
+    pub fn hex_len(&self) -> usize {
+        match self {
+            Algorithm::Sha1 => 40,
+            Algorithm::Sha224 => 56,
+            Algorithm::Sha256 => 64,
+            Algorithm::Sha384 => 96,
+            Algorithm::Sha512 => 128,
+        }
+    }
+
+    pub fn hasher(&self) -> Box<dyn DynDigest + Send> {
+        match self {
+            Algorithm::Sha1 => Box::new(sha1::Sha1::new()),
+            Algorithm::Sha224 => Box::new(sha2::Sha224::new()),
+            Algorithm::Sha256 => Box::new(sha2::Sha256::new()),
+            Algorithm::Sha384 => Box::new(sha2::Sha384::new()),
+            Algorithm::Sha512 => Box::new(sha2::Sha512::new()),
+        }
+    }
+}
+
+// Lowercase hexadecimal of a finished hash.
+pub fn hex_digest(hasher: Box<dyn DynDigest + Send>) -> String {
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn is_hex(hash: &[u8], algorithm: Algorithm) -> bool {
+    hash.len() == algorithm.hex_len() && hash.iter().all(|c| c.is_ascii_hexdigit())
+}
+
+// Raw bytes of a path; exact on Unix, where paths need not be UTF-8.
+#[cfg(unix)]
+pub fn path_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+pub fn path_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().as_bytes().to_vec()
+}
+
+#[cfg(unix)]
+pub fn path_from_bytes(bytes: Vec<u8>) -> Result<PathBuf, String> {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+    Ok(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+#[cfg(not(unix))]
+pub fn path_from_bytes(bytes: Vec<u8>) -> Result<PathBuf, String> {
+    match String::from_utf8(bytes) {
+        Ok(s) => Ok(PathBuf::from(s)),
+        Err(_) => Err(String::from("File name is not valid UTF-8")),
+    }
+}
+
+// GNU coreutils escaping: names containing backslash, newline or
+// carriage return get escaped, and the line is prefixed with a backslash.
+pub fn escape(name: &[u8]) -> (bool, Vec<u8>) {
+    let mut escaped = false;
+    let mut result: Vec<u8> = Vec::with_capacity(name.len());
+    for &c in name {
+        match c {
+            b'\\' => result.extend_from_slice(b"\\\\"),
+            b'\n' => result.extend_from_slice(b"\\n"),
+            b'\r' => result.extend_from_slice(b"\\r"),
+            _ => {
+                result.push(c);
+                continue;
+            }
+        }
+        escaped = true;
+    }
+    (escaped, result)
+}
+
+pub fn unescape(name: &[u8]) -> Result<Vec<u8>, String> {
+    let mut result: Vec<u8> = Vec::with_capacity(name.len());
+    let mut iter = name.iter();
+    while let Some(&c) = iter.next() {
+        if c != b'\\' {
+            result.push(c);
+            continue;
+        }
+        match iter.next() {
+            Some(b'\\') => result.push(b'\\'),
+            Some(b'n') => result.push(b'\n'),
+            Some(b'r') => result.push(b'\r'),
+            Some(&other) => {
+                return Err(format!("Invalid escape sequence \\{}", other as char));
+            }
+            None => return Err(String::from("Dangling backslash")),
+        }
+    }
+    Ok(result)
+}
+
+// "<hash>  <path>\n", compatible with sha256sum -c.
+pub fn format_line(hash: &str, path: &Path) -> Vec<u8> {
+    let (escaped, name) = escape(&path_bytes(path));
+    let mut line: Vec<u8> = Vec::with_capacity(name.len() + hash.len() + 4);
+    if escaped {
+        line.push(b'\\');
+    }
+    line.extend_from_slice(hash.to_lowercase().as_bytes());
+    line.extend_from_slice(b"  ");
+    line.extend_from_slice(&name);
+    line.push(b'\n');
+    line
+}
+
+// "SHA256 (<path>) = <hash>\n", as written by shasum --tag.
+pub fn format_tagged(hash: &str, path: &Path) -> Vec<u8> {
+    let (escaped, name) = escape(&path_bytes(path));
+    let mut line: Vec<u8> = Vec::with_capacity(name.len() + hash.len() + 16);
+    if escaped {
+        line.push(b'\\');
+    }
+    line.extend_from_slice(Algorithm::Sha256.tag().as_bytes());
+    line.extend_from_slice(b" (");
+    line.extend_from_slice(&name);
+    line.extend_from_slice(b") = ");
+    line.extend_from_slice(hash.to_lowercase().as_bytes());
+    line.push(b'\n');
+    line
+}
+
+// Whether a line is BSD tagged: an algorithm name, then " (". GNU lines
+// have two spaces or " *" after the hash instead.
+pub fn is_tagged(line: &[u8]) -> bool {
+    let line = line.strip_prefix(b"\\").unwrap_or(line);
+    match line.iter().position(|&c| c == b' ') {
+        Some(space) => {
+            space > 0
+                && line[..space].iter().all(|c| c.is_ascii_alphanumeric())
+                && line.get(space + 1) == Some(&b'(')
+        }
+        None => false,
+    }
+}
+
+// Split a BSD tagged line (without line terminator) into algorithm, hash
+// and unescaped file name. Names may contain ") = ", hashes cannot.
+pub fn split_tagged(line: &[u8]) -> Result<Entry, String> {
+    let (escaped, line) = match line.first() {
+        Some(b'\\') => (true, &line[1..]),
+        _ => (false, line),
+    };
+    let open = match line.windows(2).position(|w| w == b" (") {
+        Some(open) => open,
+        None => return Err(String::from("Expected \" (\"")),
+    };
+    let tag = &line[..open];
+    let algorithm = match Algorithm::from_tag(tag) {
+        Some(algorithm) => algorithm,
+        None => {
+            return Err(format!(
+                "Unsupported hash algorithm {}",
+                String::from_utf8_lossy(tag)
+            ))
+        }
+    };
+    let rest = &line[open + 2..];
+    let close = match rest.windows(4).rposition(|w| w == b") = ") {
+        Some(close) => close,
+        None => return Err(String::from("Expected \") = \"")),
+    };
+    let hash = &rest[close + 4..];
+    if !is_hex(hash, algorithm) {
+        return Err(format!("Expected hexadecimal {} hash", algorithm.tag()));
+    }
+    let name = match escaped {
+        true => unescape(&rest[..close])?,
+        false => rest[..close].to_vec(),
+    };
+    Ok(Entry {
+        algorithm,
+        hash: String::from_utf8_lossy(hash).to_lowercase(),
+        name,
+        size: None,
+    })
+}
+
+// Split a line (without line terminator) into hash and unescaped file name.
+// Accepts both text ("  ") and binary (" *") mode separators.
+pub fn split_line(line: &[u8]) -> Result<(String, Vec<u8>), String> {
+    let (escaped, line) = match line.first() {
+        Some(b'\\') => (true, &line[1..]),
+        _ => (false, line),
+    };
+    if line.len() < 67 {
+        return Err(String::from("Too short"));
+    }
+    match &line[64..66] {
+        b"  " | b" *" => (),
+        _ => return Err(String::from("Expected 2 spaces")),
+    }
+    let hash = &line[..64];
+    if !hash.iter().all(|c| c.is_ascii_hexdigit()) {
+        return Err(String::from("Expected hexadecimal hash"));
+    }
+    let hash = String::from_utf8_lossy(hash).to_lowercase();
+    let filename = if escaped {
+        unescape(&line[66..])?
+    } else {
+        line[66..].to_vec()
+    };
+    Ok((hash, filename))
+}
+
+// "<source>\t<destination>\n" line of a renames*.txt file. Tabs are
+// escaped as \t, besides the escapes of shasum files.
+pub fn format_rename(from: &Path, to: &Path) -> Vec<u8> {
+    let mut escaped = false;
+    let mut fields: Vec<Vec<u8>> = Vec::with_capacity(2);
+    for path in [from, to] {
+        let (e, name) = escape(&path_bytes(path));
+        let mut field: Vec<u8> = Vec::with_capacity(name.len());
+        for c in name {
+            if c == b'\t' {
+                field.extend_from_slice(b"\\t");
+                escaped = true;
+            } else {
+                field.push(c);
+            }
+        }
+        escaped |= e;
+        fields.push(field);
+    }
+    let mut line: Vec<u8> = Vec::with_capacity(fields[0].len() + fields[1].len() + 3);
+    if escaped {
+        line.push(b'\\');
+    }
+    line.extend_from_slice(&fields[0]);
+    line.push(b'\t');
+    line.extend_from_slice(&fields[1]);
+    line.push(b'\n');
+    line
+}
+
+// A listed file, as read from a shasum file. Size is known only from
+// extended manifests.
+pub struct Entry {
+    pub algorithm: Algorithm,
+    pub hash: String,
+    pub name: Vec<u8>,
+    pub size: Option<u64>,
+}
+
+// What an extended manifest records of a file besides its hash.
+pub struct FileInfo {
+    pub size: u64,
+    pub mtime: Option<SystemTime>,
+    pub mode: Option<u32>,
+}
+
+impl FileInfo {
+    pub fn from_metadata(metadata: &std::fs::Metadata) -> FileInfo {
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(metadata.permissions().mode())
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+        FileInfo {
+            size: metadata.len(),
+            mtime: metadata.modified().ok(),
+            mode,
+        }
+    }
+}
+
+// One line of a shasum*.jsonl file: a header, then one record per file.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Record {
+    Header(Header),
+    File(FileRecord),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Header {
+    pub tool: String,
+    pub version: String,
+    pub host: String,
+    pub source: String,
+    pub destination: String,
+    pub created: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FileRecord {
+    // UTF-8 names as is, other names base64 encoded in path_bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_bytes: Option<String>,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<String>,
+    // Permission bits, in octal.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    pub algorithm: String,
+    pub hash: String,
+    pub copied: String,
+}
+
+// Header of an extended manifest. Roots are made absolute where possible.
+pub fn header(source: &Path, destination: &Path, created: &DateTime<Local>) -> Record {
+    let source = source.canonicalize().unwrap_or(source.to_path_buf());
+    let destination = destination
+        .canonicalize()
+        .unwrap_or(destination.to_path_buf());
+    Record::Header(Header {
+        tool: String::from(env!("CARGO_PKG_NAME")),
+        version: String::from(env!("CARGO_PKG_VERSION")),
+        host: hostname(),
+        source: source.to_string_lossy().to_string(),
+        destination: destination.to_string_lossy().to_string(),
+        created: created.to_rfc3339(),
+    })
+}
+
+// A name in JSON: UTF-8 names as path, other names base64 encoded as
+// path_bytes.
+pub fn json_name(path: &Path) -> (Option<String>, Option<String>) {
+    match String::from_utf8(path_bytes(path)) {
+        Ok(path) => (Some(path), None),
+        Err(e) => (None, Some(BASE64.encode(e.as_bytes()))),
+    }
+}
+
+pub fn name_from_json(path: Option<String>, path_bytes: Option<String>) -> Result<Vec<u8>, String> {
+    match (path, path_bytes) {
+        (Some(path), None) => Ok(path.into_bytes()),
+        (None, Some(encoded)) => BASE64
+            .decode(encoded)
+            .map_err(|_| String::from("Invalid path_bytes")),
+        _ => Err(String::from("Expected one of path and path_bytes")),
+    }
+}
+
+pub fn file_record(hash: &str, path: &Path, info: &FileInfo) -> Record {
+    let (path, path_bytes) = json_name(path);
+    Record::File(FileRecord {
+        path,
+        path_bytes,
+        size: info.size,
+        mtime: info.mtime.map(|t| DateTime::<Local>::from(t).to_rfc3339()),
+        mode: info.mode.map(|m| format!("{:04o}", m & 0o7777)),
+        algorithm: String::from("sha256"),
+        hash: hash.to_lowercase(),
+        copied: Local::now().to_rfc3339(),
+    })
+}
+
+// The record as one line of JSON.
+pub fn format_record(record: &Record) -> Vec<u8> {
+    let mut line = serde_json::to_vec(record).unwrap_or_default();
+    line.push(b'\n');
+    line
+}
+
+// The listed file of a record, or None for the header.
+pub fn parse_record(line: &[u8]) -> Result<Option<Entry>, String> {
+    let record: Record = serde_json::from_slice(line).map_err(|e| e.to_string())?;
+    let file = match record {
+        Record::Header(_) => return Ok(None),
+        Record::File(file) => file,
+    };
+    let algorithm = match Algorithm::from_tag(file.algorithm.as_bytes()) {
+        Some(algorithm) => algorithm,
+        None => return Err(format!("Unsupported hash algorithm {}", file.algorithm)),
+    };
+    if !is_hex(file.hash.as_bytes(), algorithm) {
+        return Err(format!("Expected hexadecimal {} hash", algorithm.tag()));
+    }
+    let name = name_from_json(file.path, file.path_bytes)?;
+    Ok(Some(Entry {
+        algorithm,
+        hash: file.hash.to_lowercase(),
+        name,
+        size: Some(file.size),
+    }))
+}
+
+#[cfg(unix)]
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let rc = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if rc != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).to_string()
+}
+
+#[cfg(not(unix))]
+pub fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
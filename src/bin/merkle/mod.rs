@@ -0,0 +1,102 @@
+// Tree hash of a listed directory tree: one fingerprint over the sorted
+// names and hashes of all files, and the directories containing them.
+// Shared between dircopy and dirverify; each binary uses a subset.
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::path::MAIN_SEPARATOR;
+
+use crate::manifest::hex_digest;
+use crate::manifest::Algorithm;
+
+// Suffix of tree hash files, beside the shasum file.
+pub const TREE_SUFFIX: &str = ".tree";
+
+// Name of the tree hash file of a shasum file.
+pub fn tree_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(TREE_SUFFIX);
+    PathBuf::from(name)
+}
+
+#[derive(Default)]
+struct Dir {
+    files: BTreeMap<Vec<u8>, (Algorithm, String)>,
+    dirs: BTreeMap<Vec<u8>, Dir>,
+}
+
+impl Dir {
+    // SHA-256 over the entries, in byte order of their names:
+    //   "F" name NUL algorithm ":" hash LF   for files,
+    //   "D" name NUL tree hash LF            for directories.
+    // Names cannot contain NUL, so entries cannot be confused.
+    fn hash(&self) -> String {
+        let mut entries: Vec<(&[u8], u8, String)> = Vec::new();
+        for (name, (algorithm, hash)) in &self.files {
+            entries.push((name, b'F', format!("{}:{}", algorithm.tag(), hash)));
+        }
+        for (name, dir) in &self.dirs {
+            entries.push((name, b'D', dir.hash()));
+        }
+        entries.sort();
+        let mut hasher = Algorithm::Sha256.hasher();
+        for (name, kind, value) in entries {
+            hasher.update(&[kind]);
+            hasher.update(name);
+            hasher.update(b"\0");
+            hasher.update(value.as_bytes());
+            hasher.update(b"\n");
+        }
+        hex_digest(hasher)
+    }
+}
+
+#[derive(Default)]
+pub struct Tree {
+    root: Dir,
+}
+
+impl Tree {
+    pub fn new() -> Tree {
+        Tree::default()
+    }
+
+    // Add a file by its listed name, relative to the root. A name listed
+    // again replaces the earlier hash.
+    pub fn add(&mut self, name: &[u8], algorithm: Algorithm, hash: &str) {
+        let mut components: Vec<&[u8]> = name
+            .split(|&c| c == b'/' || c == MAIN_SEPARATOR as u8)
+            .filter(|c| !c.is_empty() && *c != b".")
+            .collect();
+        let file = match components.pop() {
+            Some(file) => file,
+            None => return,
+        };
+        let mut dir = &mut self.root;
+        for component in components {
+            dir = dir.dirs.entry(component.to_vec()).or_default();
+        }
+        dir.files
+            .insert(file.to_vec(), (algorithm, hash.to_lowercase()));
+    }
+
+    pub fn root_hash(&self) -> String {
+        self.root.hash()
+    }
+}
+
+// "<tree hash>\n", the contents of a tree hash file.
+pub fn format_root(hash: &str) -> String {
+    format!("{}\n", hash)
+}
+
+pub fn parse_root(text: &[u8]) -> Result<String, String> {
+    let line = text.split(|&c| c == b'\n').next().unwrap_or_default();
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    if line.len() != 64 || !line.iter().all(|c| c.is_ascii_hexdigit()) {
+        return Err(String::from("Expected hexadecimal tree hash"));
+    }
+    Ok(String::from_utf8_lossy(line).to_lowercase())
+}
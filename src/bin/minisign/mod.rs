@@ -0,0 +1,169 @@
+// Detached minisign signatures of shasum files: signing with a minisign
+// secret key, and verifying against trusted public keys.
+// Shared between dircopy and dirverify; each binary uses a subset.
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use blake2::digest::consts::U32;
+use blake2::{Blake2b, Blake2b512, Digest};
+use ed25519_dalek::Signer;
+use ed25519_dalek::SigningKey;
+
+// Suffix of signature files, beside the signed file.
+pub const SIG_SUFFIX: &str = ".minisig";
+
+// Name of the signature file of path.
+pub fn sig_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(SIG_SUFFIX);
+    PathBuf::from(name)
+}
+
+// Signature algorithms: Ed25519 of the data, or of its BLAKE2b-512 hash.
+const ALG_ED25519: &[u8; 2] = b"Ed";
+const ALG_ED25519_HASHED: &[u8; 2] = b"ED";
+// Key derivation of encrypted secret keys, scrypt or none.
+const KDF_SCRYPT: &[u8; 2] = b"Sc";
+const KDF_NONE: &[u8; 2] = &[0, 0];
+const CHECKSUM_BLAKE2B: &[u8; 2] = b"B2";
+
+// sig_alg, kdf_alg, chk_alg, salt, opslimit, memlimit, then the
+// (possibly encrypted) key number, key pair and checksum.
+const SECRET_KEY_LENGTH: usize = 2 + 2 + 2 + 32 + 8 + 8 + 8 + 64 + 32;
+const ENCRYPTED_OFFSET: usize = 2 + 2 + 2 + 32 + 8 + 8;
+
+pub struct SecretKey {
+    key_id: [u8; 8],
+    signing_key: SigningKey,
+}
+
+impl SecretKey {
+    // A secret key file written by minisign -G, decrypted with passphrase
+    // unless written unencrypted (minisign -G -W).
+    pub fn from_file(path: &Path, passphrase: Option<&str>) -> Result<SecretKey, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        SecretKey::decode(&text, passphrase).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn decode(text: &str, passphrase: Option<&str>) -> Result<SecretKey, String> {
+        let encoded = match text.lines().nth(1) {
+            Some(line) => line.trim(),
+            None => return Err(String::from("not a minisign secret key")),
+        };
+        let mut bin = BASE64
+            .decode(encoded)
+            .map_err(|_| String::from("not a minisign secret key"))?;
+        if bin.len() != SECRET_KEY_LENGTH
+            || &bin[0..2] != ALG_ED25519
+            || &bin[4..6] != CHECKSUM_BLAKE2B
+        {
+            return Err(String::from("unsupported minisign secret key"));
+        }
+        match &bin[2..4] {
+            kdf if kdf == KDF_NONE => (),
+            kdf if kdf == KDF_SCRYPT => {
+                let passphrase = match passphrase {
+                    Some(passphrase) => passphrase,
+                    None => return Err(String::from("secret key is encrypted, passphrase needed")),
+                };
+                let salt = &bin[6..38];
+                let opslimit = u64::from_le_bytes(bin[38..46].try_into().unwrap_or_default());
+                let memlimit = u64::from_le_bytes(bin[46..54].try_into().unwrap_or_default());
+                let mut stream = [0u8; SECRET_KEY_LENGTH - ENCRYPTED_OFFSET];
+                let (log_n, r, p) = scrypt_params(opslimit, memlimit);
+                // The length here is only for password hash strings.
+                let params = scrypt::Params::new(log_n, r, p, scrypt::Params::RECOMMENDED_LEN)
+                    .map_err(|e| format!("scrypt: {}", e))?;
+                scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut stream)
+                    .map_err(|e| format!("scrypt: {}", e))?;
+                for (b, s) in bin[ENCRYPTED_OFFSET..].iter_mut().zip(stream.iter()) {
+                    *b ^= s;
+                }
+            }
+            _ => return Err(String::from("unsupported key derivation")),
+        }
+        let key_id: [u8; 8] = bin[54..62].try_into().unwrap_or_default();
+        let keypair: [u8; 64] = bin[62..126].try_into().unwrap_or([0; 64]);
+        let mut checksum = Blake2b::<U32>::new();
+        checksum.update(ALG_ED25519);
+        checksum.update(key_id);
+        checksum.update(keypair);
+        if checksum.finalize()[..] != bin[126..158] {
+            return Err(String::from("wrong passphrase, or damaged secret key"));
+        }
+        let signing_key = SigningKey::from_keypair_bytes(&keypair).map_err(|e| e.to_string())?;
+        Ok(SecretKey {
+            key_id,
+            signing_key,
+        })
+    }
+
+    // Contents of a minisign signature file of data, prehashed as by
+    // minisign 0.8 and later.
+    pub fn sign(&self, data: &[u8], trusted_comment: &str) -> String {
+        let hash = Blake2b512::digest(data);
+        let signature = self.signing_key.sign(&hash).to_bytes();
+        let mut bin1: Vec<u8> = Vec::new();
+        bin1.extend_from_slice(ALG_ED25519_HASHED);
+        bin1.extend_from_slice(&self.key_id);
+        bin1.extend_from_slice(&signature);
+        let mut global: Vec<u8> = signature.to_vec();
+        global.extend_from_slice(trusted_comment.as_bytes());
+        let global_signature = self.signing_key.sign(&global).to_bytes();
+        format!(
+            "untrusted comment: signature from dircopy secret key\n{}\ntrusted comment: {}\n{}\n",
+            BASE64.encode(bin1),
+            trusted_comment,
+            BASE64.encode(global_signature)
+        )
+    }
+}
+
+// scrypt parameters (log2 N, r, p) for libsodium limits, as chosen by
+// crypto_pwhash_scryptsalsa208sha256.
+fn scrypt_params(opslimit: u64, memlimit: u64) -> (u8, u32, u32) {
+    let opslimit = opslimit.max(32768);
+    let r: u64 = 8;
+    let max_n = match opslimit < memlimit / 32 {
+        true => opslimit / (r * 4),
+        false => memlimit / (r * 128),
+    };
+    let mut log_n: u8 = 1;
+    while log_n < 63 && (1u64 << log_n) <= max_n / 2 {
+        log_n += 1;
+    }
+    if opslimit < memlimit / 32 {
+        return (log_n, r as u32, 1);
+    }
+    let max_rp = ((opslimit / 4) / (1u64 << log_n)).min(0x3fff_ffff);
+    (log_n, r as u32, (max_rp / r) as u32)
+}
+
+// A minisign public key file, as written by minisign -G.
+pub fn public_key_from_file(path: &Path) -> Result<minisign_verify::PublicKey, String> {
+    minisign_verify::PublicKey::from_file(path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// Check a detached signature of data against the trusted keys.
+pub fn verify(
+    keys: &[minisign_verify::PublicKey],
+    data: &[u8],
+    signature: &str,
+) -> Result<(), String> {
+    let signature = minisign_verify::Signature::decode(signature).map_err(|e| e.to_string())?;
+    let mut result = Err(String::from("no trusted key"));
+    for key in keys {
+        result = key
+            .verify(data, &signature, false)
+            .map_err(|e| e.to_string());
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
+}
@@ -0,0 +1,232 @@
+// Reed-Solomon recovery data of copied files, and repair of damaged files
+// with it.
+// Shared between dircopy and dirverify; each binary uses a subset.
+#![allow(dead_code)]
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use sha2::{Digest, Sha256};
+
+use crate::iotools;
+
+// Suffix of recovery data files, beside the file.
+pub const PARITY_SUFFIX: &str = ".parity";
+
+// Name of the recovery data file of path.
+pub fn parity_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(PARITY_SUFFIX);
+    PathBuf::from(name)
+}
+
+// A recovery data file is a header, then for each stripe of the file the
+// SHA-256 of its data and parity shards, followed by its parity shards.
+// Stripes are MAX_DATA_SHARDS shards, except the last; the last data
+// shard is zero padded. A shard is damaged if its hash differs, and a
+// stripe can be repaired unless more shards are damaged than it has
+// parity shards.
+const MAGIC: &[u8; 8] = b"DCPAR001";
+// Magic, file size, shard size, percentage of parity shards.
+const HEADER_LEN: usize = 8 + 8 + 4 + 4;
+const HASH_LEN: usize = 32;
+const MAX_DATA_SHARDS: usize = 100;
+const MIN_SHARD_SIZE: usize = 64;
+const MAX_SHARD_SIZE: usize = 64 * 1024;
+
+pub fn valid_percent(percent: u32) -> Result<(), String> {
+    match percent {
+        1..=100 => Ok(()),
+        _ => Err(format!("Parity percentage {} not within 1-100", percent)),
+    }
+}
+
+// Small files get small shards, so that one stripe covers them.
+fn shard_size(size: u64) -> usize {
+    let shard_size = size.div_ceil(MAX_DATA_SHARDS as u64);
+    shard_size.clamp(MIN_SHARD_SIZE as u64, MAX_SHARD_SIZE as u64) as usize
+}
+
+fn parity_shards(data_shards: usize, percent: u32) -> usize {
+    (data_shards * percent as usize).div_ceil(100).max(1)
+}
+
+fn sha256(data: &[u8]) -> [u8; HASH_LEN] {
+    Sha256::digest(data).into()
+}
+
+// Data and parity shards of the data of a stripe.
+fn encode(data: &[u8], shard_size: usize, percent: u32) -> io::Result<Vec<Vec<u8>>> {
+    let data_shards = data.len().div_ceil(shard_size);
+    let parity_shards = parity_shards(data_shards, percent);
+    let mut shards: Vec<Vec<u8>> = Vec::with_capacity(data_shards + parity_shards);
+    for chunk in data.chunks(shard_size) {
+        let mut shard = chunk.to_vec();
+        shard.resize(shard_size, 0);
+        shards.push(shard);
+    }
+    shards.resize(data_shards + parity_shards, vec![0; shard_size]);
+    ReedSolomon::new(data_shards, parity_shards)
+        .and_then(|rs| rs.encode(&mut shards))
+        .map_err(|e| io::Error::other(format!("{:?}", e)))?;
+    Ok(shards)
+}
+
+// Writes the recovery data of data written to it.
+pub struct Encoder {
+    file: File,
+    shard_size: usize,
+    percent: u32,
+    size: u64,
+    stripe: Vec<u8>,
+}
+
+impl Encoder {
+    // The expected size of the file picks the shard size.
+    pub fn create(path: &Path, size: u64, percent: u32) -> io::Result<Encoder> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        // Completed by finish, once the size is known.
+        file.write_all(&[0; HEADER_LEN])?;
+        Ok(Encoder {
+            file,
+            shard_size: shard_size(size),
+            percent,
+            size: 0,
+            stripe: Vec::new(),
+        })
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) -> io::Result<()> {
+        let capacity = self.shard_size * MAX_DATA_SHARDS;
+        while !data.is_empty() {
+            let n = data.len().min(capacity - self.stripe.len());
+            self.stripe.extend_from_slice(&data[..n]);
+            self.size += n as u64;
+            data = &data[n..];
+            if self.stripe.len() == capacity {
+                self.write_stripe()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_stripe(&mut self) -> io::Result<()> {
+        let shards = encode(&self.stripe, self.shard_size, self.percent)?;
+        let data_shards = self.stripe.len().div_ceil(self.shard_size);
+        let mut record: Vec<u8> = Vec::new();
+        for shard in &shards {
+            record.extend_from_slice(&sha256(shard));
+        }
+        for shard in &shards[data_shards..] {
+            record.extend_from_slice(shard);
+        }
+        self.file.write_all(&record)?;
+        self.stripe.clear();
+        Ok(())
+    }
+
+    pub fn finish(mut self, sync: bool) -> io::Result<()> {
+        if !self.stripe.is_empty() {
+            self.write_stripe()?;
+        }
+        let mut header: Vec<u8> = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&self.size.to_le_bytes());
+        header.extend_from_slice(&(self.shard_size as u32).to_le_bytes());
+        header.extend_from_slice(&self.percent.to_le_bytes());
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        if sync {
+            self.file.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+fn read_exact(reader: &mut impl Read, len: usize) -> Result<Vec<u8>, String> {
+    let mut buf = vec![0; len];
+    match iotools::read_full(reader, &mut buf) {
+        Ok(n) if n == len => Ok(buf),
+        Ok(_) => Err(String::from("recovery data truncated")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Write the data of a damaged file, repaired with its recovery data, to
+// out. Returns the number of damaged data shards restored. Fails if a
+// stripe is damaged beyond repair.
+pub fn repair(
+    mut data: impl Read,
+    mut parity: impl Read,
+    out: &mut impl Write,
+) -> Result<usize, String> {
+    let header = read_exact(&mut parity, HEADER_LEN)?;
+    if &header[0..8] != MAGIC {
+        return Err(String::from("not a recovery data file"));
+    }
+    let size = u64::from_le_bytes(header[8..16].try_into().unwrap_or_default());
+    let shard_size = u32::from_le_bytes(header[16..20].try_into().unwrap_or_default()) as usize;
+    let percent = u32::from_le_bytes(header[20..24].try_into().unwrap_or_default());
+    if !(MIN_SHARD_SIZE..=MAX_SHARD_SIZE).contains(&shard_size) || valid_percent(percent).is_err() {
+        return Err(String::from("unsupported recovery data"));
+    }
+    let capacity = (shard_size * MAX_DATA_SHARDS) as u64;
+    let mut remaining = size;
+    let mut restored: usize = 0;
+    let mut stripe: usize = 0;
+    while remaining > 0 {
+        let stripe_len = remaining.min(capacity) as usize;
+        let data_shards = stripe_len.div_ceil(shard_size);
+        let parity_shards = parity_shards(data_shards, percent);
+        let total = data_shards + parity_shards;
+        let hashes = read_exact(&mut parity, total * HASH_LEN)?;
+        let parity_data = read_exact(&mut parity, parity_shards * shard_size)?;
+        // A truncated file reads as zeros, failing the shard hashes.
+        let mut stripe_data = vec![0; data_shards * shard_size];
+        if let Err(e) = iotools::read_full(&mut data, &mut stripe_data[..stripe_len]) {
+            return Err(e.to_string());
+        }
+        let mut shards: Vec<Option<Vec<u8>>> = stripe_data
+            .chunks(shard_size)
+            .chain(parity_data.chunks(shard_size))
+            .map(|shard| shard.to_vec())
+            .zip(hashes.chunks(HASH_LEN))
+            .map(|(shard, hash)| (sha256(&shard) == hash).then_some(shard))
+            .collect();
+        let damaged = shards.iter().filter(|shard| shard.is_none()).count();
+        if damaged > parity_shards {
+            return Err(format!(
+                "stripe {}: {} damaged shards, only {} parity shards",
+                stripe, damaged, parity_shards
+            ));
+        }
+        let damaged_data = shards[..data_shards].iter().filter(|s| s.is_none()).count();
+        if damaged_data > 0 {
+            ReedSolomon::new(data_shards, parity_shards)
+                .and_then(|rs| rs.reconstruct_data(&mut shards))
+                .map_err(|e| format!("stripe {}: {:?}", stripe, e))?;
+            restored += damaged_data;
+        }
+        let mut written = 0;
+        for shard in shards[..data_shards].iter().flatten() {
+            let n = shard.len().min(stripe_len - written);
+            out.write_all(&shard[..n]).map_err(|e| e.to_string())?;
+            written += n;
+        }
+        remaining -= stripe_len as u64;
+        stripe += 1;
+    }
+    Ok(restored)
+}
@@ -0,0 +1,189 @@
+// Busy and waiting time of the stages of the read/hash/write pipeline, and
+// how often the queues between them are full or empty, telling which
+// stage limits throughput.
+// Shared between dircopy and dirverify; each binary uses a subset.
+#![allow(dead_code)]
+
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvError;
+use std::sync::mpsc::SendError;
+use std::sync::mpsc::SyncSender;
+use std::sync::mpsc::TryRecvError;
+use std::sync::mpsc::TrySendError;
+use std::time::Instant;
+
+#[derive(Clone, Copy)]
+pub enum Stage {
+    Read,
+    Hash,
+    Write,
+}
+
+const STAGE_NAMES: [&str; 3] = ["read", "hash", "write"];
+
+// Named after the sender feeding each queue.
+#[derive(Clone, Copy)]
+pub enum Queue {
+    Read,
+    Sha,
+    FileWrite,
+}
+
+const QUEUE_NAMES: [&str; 3] = ["read_tx", "sha_tx", "file_write_tx"];
+
+// Nanoseconds spent working, and waiting for a queue or buffer.
+#[derive(Default)]
+struct StageTimes {
+    busy: AtomicU64,
+    waiting: AtomicU64,
+}
+
+#[derive(Default)]
+struct QueueFill {
+    sends: AtomicU64,
+    full: AtomicU64,
+    receives: AtomicU64,
+    empty: AtomicU64,
+}
+
+// Shared by the threads of all files copied or verified.
+#[derive(Default)]
+pub struct Pipeline {
+    stages: [StageTimes; 3],
+    queues: [QueueFill; 3],
+}
+
+fn add_since(counter: &AtomicU64, since: Instant) -> Instant {
+    let now = Instant::now();
+    counter.fetch_add((now - since).as_nanos() as u64, Ordering::Relaxed);
+    now
+}
+
+fn percent(part: u64, whole: u64) -> u64 {
+    (100 * part).checked_div(whole).unwrap_or(0)
+}
+
+impl Pipeline {
+    // Account the time since since as working; returns now, to account
+    // the next interval from.
+    pub fn busy(&self, stage: Stage, since: Instant) -> Instant {
+        add_since(&self.stages[stage as usize].busy, since)
+    }
+
+    // Account the time since since as waiting; returns now.
+    pub fn waiting(&self, stage: Stage, since: Instant) -> Instant {
+        add_since(&self.stages[stage as usize].waiting, since)
+    }
+
+    // Account the time since since as working for stage, and as waiting
+    // for other, the stage sharing its thread; returns now.
+    pub fn busy_serial(&self, stage: Stage, other: Stage, since: Instant) -> Instant {
+        let now = Instant::now();
+        let nanos = (now - since).as_nanos() as u64;
+        let times = &self.stages;
+        times[stage as usize]
+            .busy
+            .fetch_add(nanos, Ordering::Relaxed);
+        times[other as usize]
+            .waiting
+            .fetch_add(nanos, Ordering::Relaxed);
+        now
+    }
+
+    // Send, noting whether the queue was full.
+    pub fn send<T>(
+        &self,
+        queue: Queue,
+        tx: &SyncSender<T>,
+        message: T,
+    ) -> Result<(), SendError<T>> {
+        let fill = &self.queues[queue as usize];
+        fill.sends.fetch_add(1, Ordering::Relaxed);
+        match tx.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(message)) => {
+                fill.full.fetch_add(1, Ordering::Relaxed);
+                tx.send(message)
+            }
+            Err(TrySendError::Disconnected(message)) => Err(SendError(message)),
+        }
+    }
+
+    // Receive, noting whether the queue was empty.
+    pub fn recv<T>(&self, queue: Queue, rx: &Receiver<T>) -> Result<T, RecvError> {
+        let fill = &self.queues[queue as usize];
+        fill.receives.fetch_add(1, Ordering::Relaxed);
+        match rx.try_recv() {
+            Ok(message) => Ok(message),
+            Err(TryRecvError::Empty) => {
+                fill.empty.fetch_add(1, Ordering::Relaxed);
+                rx.recv()
+            }
+            Err(TryRecvError::Disconnected) => Err(RecvError),
+        }
+    }
+
+    // The stage working the longest; the others wait for it.
+    pub fn bottleneck(&self) -> Option<&'static str> {
+        let (index, busy) = self
+            .stages
+            .iter()
+            .map(|times| times.busy.load(Ordering::Relaxed))
+            .enumerate()
+            .max_by_key(|(_, busy)| *busy)?;
+        match busy {
+            0 => None,
+            _ => Some(STAGE_NAMES[index]),
+        }
+    }
+
+    // Share of time each stage was busy, e.g. "read busy 40%, hash busy 97%".
+    pub fn describe_stages(&self) -> Option<String> {
+        let stages: Vec<String> = self
+            .stages
+            .iter()
+            .zip(STAGE_NAMES)
+            .filter_map(|(times, name)| {
+                let busy = times.busy.load(Ordering::Relaxed);
+                let total = busy + times.waiting.load(Ordering::Relaxed);
+                match total {
+                    0 => None,
+                    _ => Some(format!("{} busy {}%", name, percent(busy, total))),
+                }
+            })
+            .collect();
+        match stages.is_empty() {
+            true => None,
+            false => Some(stages.join(", ")),
+        }
+    }
+
+    // How often each queue was full when sent to, and empty when
+    // received from, e.g. "sha_tx full 95% empty 0%".
+    pub fn describe_queues(&self) -> Option<String> {
+        let queues: Vec<String> = self
+            .queues
+            .iter()
+            .zip(QUEUE_NAMES)
+            .filter_map(|(fill, name)| {
+                let sends = fill.sends.load(Ordering::Relaxed);
+                let receives = fill.receives.load(Ordering::Relaxed);
+                match sends + receives {
+                    0 => None,
+                    _ => Some(format!(
+                        "{} full {}% empty {}%",
+                        name,
+                        percent(fill.full.load(Ordering::Relaxed), sends),
+                        percent(fill.empty.load(Ordering::Relaxed), receives)
+                    )),
+                }
+            })
+            .collect();
+        match queues.is_empty() {
+            true => None,
+            false => Some(queues.join(", ")),
+        }
+    }
+}
@@ -0,0 +1,539 @@
+// Writing and reading of POSIX pax (tar) archives, one member at a time,
+// so that member data can pass through the reader/hasher/writer pipeline.
+// Shared between dircopy and dirverify; each binary uses a subset.
+#![allow(dead_code)]
+
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+
+use tar::EntryType;
+use tar::Header;
+use tar::HeaderMode;
+use tar::PaxExtensions;
+
+use crate::manifest;
+
+const BLOCK: u64 = 512;
+
+// pax extended header keyword of the SHA-256 of member data.
+pub const PAX_SHA256: &str = "DIRCOPY.sha256";
+
+// Largest size the ustar size field holds in octal, 8 GiB - 1.
+const USTAR_MAX_SIZE: u64 = 0o77777777777;
+
+enum Sink {
+    File(File),
+    Stdout(io::Stdout),
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::File(f) => f.write(buf),
+            Sink::Stdout(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::File(f) => f.flush(),
+            Sink::Stdout(s) => s.flush(),
+        }
+    }
+}
+
+pub struct TarWriter {
+    out: BufWriter<Sink>,
+    position: u64,
+    // Member being written: start of data, size, and where to patch the hash.
+    data_start: u64,
+    size: u64,
+    hash_offset: Option<u64>,
+}
+
+impl Write for TarWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.out.write(buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl TarWriter {
+    // Create a new archive file; existing files are never overwritten.
+    pub fn create(path: &Path) -> io::Result<TarWriter> {
+        let file = File::options().write(true).create_new(true).open(path)?;
+        Ok(TarWriter::new(Sink::File(file)))
+    }
+
+    pub fn stdout() -> TarWriter {
+        TarWriter::new(Sink::Stdout(io::stdout()))
+    }
+
+    fn new(sink: Sink) -> TarWriter {
+        TarWriter {
+            out: BufWriter::new(sink),
+            position: 0,
+            data_start: 0,
+            size: 0,
+            hash_offset: None,
+        }
+    }
+
+    // A file archive can have member hashes patched in after the data.
+    // On stdout, the hash must be known before the member is written.
+    pub fn seekable(&self) -> bool {
+        matches!(self.out.get_ref(), Sink::File(_))
+    }
+
+    pub fn add_directory(&mut self, path: &Path, metadata: &std::fs::Metadata) -> io::Result<()> {
+        let mut header = Header::new_ustar();
+        header.set_metadata_in_mode(metadata, HeaderMode::Complete);
+        header.set_entry_type(EntryType::Directory);
+        header.set_size(0);
+        let mut name = member_name(path);
+        name.push(b'/');
+        self.write_header(header, &name, None)?;
+        Ok(())
+    }
+
+    // Write headers of a file member. hash is None when it is not yet
+    // known; a placeholder is then patched by end_file.
+    pub fn begin_file(
+        &mut self,
+        path: &Path,
+        metadata: &std::fs::Metadata,
+        hash: Option<&str>,
+    ) -> io::Result<()> {
+        if hash.is_none() && !self.seekable() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "member hash required when archive is not seekable",
+            ));
+        }
+        let mut header = Header::new_ustar();
+        header.set_metadata_in_mode(metadata, HeaderMode::Complete);
+        header.set_entry_type(EntryType::Regular);
+        header.set_size(metadata.len());
+        let placeholder = "0".repeat(64);
+        let hash_offset = self.write_header(
+            header,
+            &member_name(path),
+            Some(hash.unwrap_or(&placeholder)),
+        )?;
+        self.data_start = self.position;
+        self.size = metadata.len();
+        self.hash_offset = match hash {
+            Some(_) => None,
+            None => hash_offset,
+        };
+        Ok(())
+    }
+
+    // Pad member data to a full block, and patch in its hash if needed.
+    pub fn end_file(&mut self, hash: &str) -> io::Result<()> {
+        let written = self.position - self.data_start;
+        if written != self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "file changed size while archiving: {} bytes expected, {} written",
+                    self.size, written
+                ),
+            ));
+        }
+        self.pad()?;
+        if let Some(offset) = self.hash_offset.take() {
+            self.out.flush()?;
+            if let Sink::File(f) = self.out.get_mut() {
+                f.seek(SeekFrom::Start(offset))?;
+                f.write_all(hash.as_bytes())?;
+                f.seek(SeekFrom::Start(self.position))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Member whose data is known up front, e.g. the manifest.
+    pub fn add_bytes(&mut self, path: &Path, data: &[u8], mtime: u64) -> io::Result<()> {
+        let mut header = Header::new_ustar();
+        header.set_entry_type(EntryType::Regular);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        header.set_size(data.len() as u64);
+        self.write_header(header, &member_name(path), None)?;
+        self.write_all(data)?;
+        self.pad()
+    }
+
+    // End of archive marker; the archive is complete once flushed.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.write_all(&[0u8; 2 * BLOCK as usize])?;
+        self.out.flush()
+    }
+
+    // Make everything written so far durable (file archives only).
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        match self.out.get_ref() {
+            Sink::File(f) => f.sync_data(),
+            Sink::Stdout(_) => Ok(()),
+        }
+    }
+
+    fn pad(&mut self) -> io::Result<()> {
+        let rest = self.position % BLOCK;
+        if rest != 0 {
+            let zeros = [0u8; BLOCK as usize];
+            self.write_all(&zeros[..(BLOCK - rest) as usize])?;
+        }
+        Ok(())
+    }
+
+    // Write a pax extended header for what ustar cannot hold, then the
+    // ustar header itself. Returns the archive offset of the hash value.
+    fn write_header(
+        &mut self,
+        mut header: Header,
+        name: &[u8],
+        hash: Option<&str>,
+    ) -> io::Result<Option<u64>> {
+        let mut records: Vec<u8> = Vec::new();
+        if !set_ustar_name(&mut header, name) {
+            records.extend_from_slice(&pax_record("path", name));
+        }
+        let size = header.size()?;
+        if size > USTAR_MAX_SIZE {
+            records.extend_from_slice(&pax_record("size", size.to_string().as_bytes()));
+        }
+        let mut hash_offset: Option<u64> = None;
+        if let Some(hash) = hash {
+            let record = pax_record(PAX_SHA256, hash.as_bytes());
+            // Value is last in the record, before the newline.
+            hash_offset = Some((records.len() + record.len() - 1 - hash.len()) as u64);
+            records.extend_from_slice(&record);
+        }
+        if !records.is_empty() {
+            let mut pax = Header::new_ustar();
+            pax.set_entry_type(EntryType::XHeader);
+            pax.set_mode(0o644);
+            pax.set_size(records.len() as u64);
+            set_ustar_name(&mut pax, b"././@PaxHeader");
+            pax.set_cksum();
+            self.write_all(pax.as_bytes())?;
+            hash_offset = hash_offset.map(|offset| self.position + offset);
+            self.write_all(&records)?;
+            self.pad()?;
+        }
+        header.set_cksum();
+        self.write_all(header.as_bytes())?;
+        Ok(hash_offset)
+    }
+}
+
+// Relative path with '/' separators, as stored in archives.
+fn member_name(path: &Path) -> Vec<u8> {
+    let mut name: Vec<u8> = Vec::new();
+    for component in path.components() {
+        if !name.is_empty() {
+            name.push(b'/');
+        }
+        name.extend_from_slice(&manifest::path_bytes(Path::new(component.as_os_str())));
+    }
+    name
+}
+
+// "<length> <keyword>=<value>\n", where length includes itself.
+fn pax_record(keyword: &str, value: &[u8]) -> Vec<u8> {
+    let rest = keyword.len() + value.len() + 3;
+    let mut length = rest + rest.to_string().len();
+    if length.to_string().len() + rest != length {
+        length += 1;
+    }
+    let mut record: Vec<u8> = format!("{} {}=", length, keyword).into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+// Store name in the ustar name and prefix fields, if it fits.
+// Otherwise a truncated name is stored, and false returned.
+fn set_ustar_name(header: &mut Header, name: &[u8]) -> bool {
+    let ustar = match header.as_ustar_mut() {
+        Some(ustar) => ustar,
+        None => return false,
+    };
+    if name.len() <= ustar.name.len() {
+        ustar.name[..name.len()].copy_from_slice(name);
+        return true;
+    }
+    // Split at a '/', with up to 155 bytes before and 100 bytes after it.
+    let split = name
+        .iter()
+        .enumerate()
+        .rev()
+        .find(|(i, c)| {
+            **c == b'/' && *i <= ustar.prefix.len() && name.len() - i - 1 <= ustar.name.len()
+        })
+        .map(|(i, _)| i);
+    match split {
+        Some(i) if i > 0 && i + 1 < name.len() => {
+            ustar.prefix[..i].copy_from_slice(&name[..i]);
+            ustar.name[..name.len() - i - 1].copy_from_slice(&name[i + 1..]);
+            true
+        }
+        _ => {
+            let n = ustar.name.len();
+            ustar.name.copy_from_slice(&name[..n]);
+            false
+        }
+    }
+}
+
+// Largest pax or GNU long name header accepted, against corrupt archives.
+const MAX_EXTENSION_SIZE: u64 = 1 << 20;
+
+#[derive(PartialEq)]
+pub enum MemberKind {
+    File,
+    Directory,
+    // Links, devices etc. carry no data of their own.
+    Other(u8),
+}
+
+pub struct Member {
+    // Raw name, as stored in the archive.
+    pub path: Vec<u8>,
+    pub size: u64,
+    pub kind: MemberKind,
+    pub mtime: u64,
+    pub mode: u32,
+    // From a DIRCOPY.sha256 pax record, if any.
+    pub sha256: Option<String>,
+}
+
+// Sequential reader of archive members. Data of the current member is
+// read through Read; unread data is skipped by next_member.
+pub struct TarReader<R: Read> {
+    inner: R,
+    remaining: u64,
+    padding: u64,
+}
+
+impl<R: Read> Read for TarReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let max = buf
+            .len()
+            .min(self.remaining.min(usize::MAX as u64) as usize);
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "archive truncated in member data",
+            ));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read> TarReader<R> {
+    pub fn new(inner: R) -> TarReader<R> {
+        TarReader {
+            inner,
+            remaining: 0,
+            padding: 0,
+        }
+    }
+
+    // Next file, directory or other member; None at end of archive.
+    pub fn next_member(&mut self) -> io::Result<Option<Member>> {
+        self.skip(self.remaining + self.padding)?;
+        self.remaining = 0;
+        self.padding = 0;
+        let mut pax_path: Option<Vec<u8>> = None;
+        let mut pax_size: Option<u64> = None;
+        let mut sha256: Option<String> = None;
+        let mut long_name: Option<Vec<u8>> = None;
+        loop {
+            let mut block = [0u8; BLOCK as usize];
+            if !self.read_block(&mut block)? {
+                return Ok(None);
+            }
+            if block.iter().all(|c| *c == 0) {
+                return Ok(None);
+            }
+            let header = Header::from_byte_slice(&block);
+            if header.cksum()? != checksum(&block) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "archive header checksum mismatch",
+                ));
+            }
+            let size = header.size()?;
+            let kind = header.entry_type();
+            if kind.is_pax_local_extensions() {
+                let data = self.read_extension(size)?;
+                for extension in PaxExtensions::new(&data) {
+                    let extension = extension?;
+                    match extension.key_bytes() {
+                        b"path" => pax_path = Some(extension.value_bytes().to_vec()),
+                        b"size" => {
+                            pax_size = String::from_utf8_lossy(extension.value_bytes())
+                                .parse::<u64>()
+                                .ok()
+                        }
+                        key if key == PAX_SHA256.as_bytes() => {
+                            sha256 = Some(
+                                String::from_utf8_lossy(extension.value_bytes()).to_lowercase(),
+                            )
+                        }
+                        _ => (),
+                    }
+                }
+                continue;
+            }
+            if kind.is_gnu_longname() {
+                let mut data = self.read_extension(size)?;
+                while data.last() == Some(&0) {
+                    data.pop();
+                }
+                long_name = Some(data);
+                continue;
+            }
+            if kind.is_pax_global_extensions() || kind.is_gnu_longlink() {
+                self.skip(size + padding(size))?;
+                continue;
+            }
+            let size = pax_size.unwrap_or(size);
+            let path = match (pax_path, long_name) {
+                (Some(path), _) => path,
+                (None, Some(name)) => name,
+                (None, None) => header.path_bytes().to_vec(),
+            };
+            let member_kind = if kind.is_file() || kind.is_contiguous() {
+                MemberKind::File
+            } else if kind.is_dir() {
+                MemberKind::Directory
+            } else {
+                MemberKind::Other(kind.as_byte())
+            };
+            self.remaining = size;
+            self.padding = padding(size);
+            return Ok(Some(Member {
+                path,
+                size,
+                kind: member_kind,
+                mtime: header.mtime().unwrap_or(0),
+                mode: header.mode().unwrap_or(0),
+                sha256,
+            }));
+        }
+    }
+
+    // False on end of input before a header.
+    fn read_block(&mut self, block: &mut [u8]) -> io::Result<bool> {
+        let mut filled = 0;
+        while filled < block.len() {
+            match self.inner.read(&mut block[filled..]) {
+                Ok(0) if filled == 0 => return Ok(false),
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "archive truncated in header",
+                    ))
+                }
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    fn read_extension(&mut self, size: u64) -> io::Result<Vec<u8>> {
+        if size > MAX_EXTENSION_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "archive extension header too large",
+            ));
+        }
+        let mut data = vec![0u8; size as usize];
+        self.inner.read_exact(&mut data)?;
+        self.skip(padding(size))?;
+        Ok(data)
+    }
+
+    fn skip(&mut self, n: u64) -> io::Result<()> {
+        let skipped = io::copy(&mut (&mut self.inner).take(n), &mut io::sink())?;
+        if skipped != n {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "archive truncated",
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn padding(size: u64) -> u64 {
+    (BLOCK - size % BLOCK) % BLOCK
+}
+
+// Header checksum, with the checksum field itself counted as spaces.
+fn checksum(block: &[u8]) -> u32 {
+    block
+        .iter()
+        .enumerate()
+        .map(|(i, c)| if (148..156).contains(&i) { b' ' } else { *c } as u32)
+        .sum()
+}
+
+// Member name as a relative path of '/' separated components, without
+// "./" and trailing slashes; empty for the archive root. None for absolute
+// names, or names with "..", which must never be written outside the
+// destination.
+pub fn clean_name(name: &[u8]) -> Option<Vec<u8>> {
+    if name.first() == Some(&b'/') {
+        return None;
+    }
+    let mut cleaned: Vec<u8> = Vec::with_capacity(name.len());
+    for component in name.split(|c| *c == b'/') {
+        match component {
+            b"" | b"." => continue,
+            b".." => return None,
+            _ => (),
+        }
+        if !cleaned.is_empty() {
+            cleaned.push(b'/');
+        }
+        cleaned.extend_from_slice(component);
+    }
+    Some(cleaned)
+}
+
+// Relative path of a cleaned member name.
+pub fn member_path(name: &[u8]) -> Result<std::path::PathBuf, String> {
+    let mut path = std::path::PathBuf::new();
+    for component in name.split(|c| *c == b'/') {
+        // Separators and drive letters of Windows would escape.
+        if cfg!(windows) && component.iter().any(|c| *c == b'\\' || *c == b':') {
+            return Err(String::from("Member name not valid on Windows"));
+        }
+        path.push(manifest::path_from_bytes(component.to_vec())?);
+    }
+    Ok(path)
+}
@@ -7,7 +7,7 @@ pub fn s2i(string: String) -> usize {
             'K' => exponent = 1024,
             'M' => exponent = 1024 * 1024,
             'G' => exponent = 1024 * 1024 * 1024,
-            '0' => prefix = prefix * 10,
+            '0' => prefix *= 10,
             '1' => prefix = prefix * 10 + 1,
             '2' => prefix = prefix * 10 + 2,
             '3' => prefix = prefix * 10 + 3,
@@ -24,14 +24,20 @@ pub fn s2i(string: String) -> usize {
     if result < 1 {
         eprintln!("Unable to parse: {}", string)
     }
-    return result;
+    result
 }
 
 pub fn bandwidth(read_bytes: usize, seconds: u64) -> String {
     if seconds == 0 {
         return String::from("NaN");
     }
-    let mut rb = (read_bytes as f64) / (seconds as f64);
+    let rb = (read_bytes as f64) / (seconds as f64);
+    format!("{}/s", size(rb))
+}
+
+// Convert 1234567 into "1.235 MB", and such
+pub fn size(bytes: f64) -> String {
+    let mut rb = bytes;
     let sufixes: Vec<&str> = vec!["B", "KB", "MB", "GB", "TB", "PB"];
     let mut suff = "";
     for s in sufixes {
@@ -39,7 +45,18 @@ pub fn bandwidth(read_bytes: usize, seconds: u64) -> String {
         if rb < 1000.0 {
             break;
         }
-        rb = rb / 1000.0;
+        rb /= 1000.0;
+    }
+    format!("{:.3} {}", rb, suff)
+}
+
+// Convert 128*1024 into "128K", and such; the inverse of s2i.
+pub fn i2s(value: usize) -> String {
+    let suffixes = [("G", 1024 * 1024 * 1024), ("M", 1024 * 1024), ("K", 1024)];
+    for (suffix, exponent) in suffixes {
+        if value >= exponent && value.is_multiple_of(exponent) {
+            return format!("{}{}", value / exponent, suffix);
+        }
     }
-    return format!("{:.3} {}/s", rb, suff);
+    value.to_string()
 }
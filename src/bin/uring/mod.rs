@@ -0,0 +1,737 @@
+// io_uring I/O backend, an alternative to the reader/hasher/writer threads.
+// Shared between dircopy and dirverify; each binary uses a subset.
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+#[cfg(target_os = "linux")]
+pub use linux::Uring;
+
+#[cfg(not(target_os = "linux"))]
+pub use unsupported::Uring;
+
+thread_local! {
+    static RING: RefCell<Option<Uring>> = const { RefCell::new(None) };
+}
+
+// Run f with this thread's ring, creating it on first use.
+// Rings and their registered buffers are reused between files,
+// unless broken by a failed wait.
+pub fn with_ring<R>(
+    depth: usize,
+    block_size: usize,
+    f: impl FnOnce(&mut Uring) -> io::Result<R>,
+) -> io::Result<R> {
+    RING.with(|cell| {
+        let mut ring = cell.borrow_mut();
+        let reuse = match ring.as_ref() {
+            Some(r) => r.depth() == depth && r.block_size() == block_size,
+            None => false,
+        };
+        if !reuse {
+            *ring = None;
+            *ring = Some(Uring::new(depth, block_size)?);
+        }
+        let result = match ring.as_mut() {
+            Some(r) => f(r),
+            None => Err(io::Error::from(io::ErrorKind::Unsupported)),
+        };
+        // The kernel may still own its buffers; closing the ring cancels
+        // what is in flight before the buffers are freed.
+        if ring.as_ref().is_some_and(|r| r.broken()) {
+            *ring = None;
+        }
+        result
+    })
+}
+
+// Check if io_uring can be used at all, e.g. not blocked by seccomp.
+pub fn probe(depth: usize, block_size: usize) -> io::Result<()> {
+    with_ring(depth, block_size, |_| Ok(()))
+}
+
+// Copy len bytes from input to output (if any), returning SHA-256 of data.
+// progress is called with the number of bytes hashed, in file order.
+pub fn transfer(
+    ring: &mut Uring,
+    input: &File,
+    output: Option<&File>,
+    len: u64,
+    direct: bool,
+    progress: &mut dyn FnMut(usize),
+) -> io::Result<String> {
+    ring.transfer(input, output, len, direct, progress)
+}
+
+// Copy files of len bytes, at most one block, from input to output (if
+// any), several at a time, returning the SHA-256 of each, or why it
+// failed. progress is called with the number of bytes hashed.
+// sync syncs each output before it is closed.
+pub fn transfer_files(
+    ring: &mut Uring,
+    files: &[(&Path, Option<&Path>, u64)],
+    sync: bool,
+    progress: &mut dyn FnMut(usize),
+) -> io::Result<Vec<io::Result<String>>> {
+    ring.transfer_files(files, sync, progress)
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    use io_uring::{opcode, squeue, types, IoUring};
+    use sha2::{Digest, Sha256};
+
+    use crate::iotools;
+    use crate::iotools::AlignedBuffer;
+
+    const OP_READ: u64 = 0;
+    const OP_WRITE: u64 = 1;
+
+    // Operations of the chain copying a small file, in order.
+    const FILE_OPEN_INPUT: u64 = 0;
+    const FILE_READ: u64 = 1;
+    const FILE_OPEN_OUTPUT: u64 = 2;
+    const FILE_WRITE: u64 = 3;
+    const FILE_SYNC: u64 = 4;
+    const FILE_CLOSE: u64 = 5;
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum SlotState {
+        Free,
+        Reading,
+        // Read completed; waiting to be hashed and written.
+        Filled,
+    }
+
+    #[derive(Clone, Copy)]
+    struct Slot {
+        state: SlotState,
+        offset: u64,
+        len: usize,
+        written: usize,
+        writing: bool,
+        hashed: bool,
+    }
+
+    pub struct Uring {
+        ring: IoUring,
+        // Registered with the kernel; must outlive the ring.
+        buffers: Vec<AlignedBuffer>,
+        slots: Vec<Slot>,
+        block_size: usize,
+        // Waiting failed with operations in flight; not to be reused.
+        broken: bool,
+        // A direct descriptor table, for the input and output of each slot,
+        // is registered.
+        files: bool,
+    }
+
+    // A small file being copied through a slot.
+    #[derive(Default)]
+    struct FileSlot {
+        file: usize,
+        // Operations submitted and not completed.
+        pending: usize,
+        hash: Option<String>,
+        error: Option<io::Error>,
+        // Closing what a failed chain left open.
+        closing: bool,
+    }
+
+    fn c_path(path: &Path) -> io::Result<CString> {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    fn descriptor_slot(index: u32) -> io::Result<types::DestinationSlot> {
+        types::DestinationSlot::try_from_slot_target(index)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "io_uring queue too deep"))
+    }
+
+    impl Uring {
+        pub fn new(depth: usize, block_size: usize) -> io::Result<Uring> {
+            let depth = depth.clamp(1, 1024);
+            if block_size == 0 || block_size > u32::MAX as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "io_uring block size out of range",
+                ));
+            }
+            // Each slot may have a read and a write in flight, or the chain
+            // of up to seven operations copying a small file.
+            let ring = IoUring::new((8 * depth).next_power_of_two() as u32)?;
+            let mut buffers: Vec<AlignedBuffer> = Vec::with_capacity(depth);
+            for _ in 0..depth {
+                buffers.push(AlignedBuffer::new(block_size));
+            }
+            let iovecs: Vec<libc::iovec> = buffers
+                .iter_mut()
+                .map(|b| libc::iovec {
+                    iov_base: b.as_mut_ptr() as *mut libc::c_void,
+                    iov_len: b.len(),
+                })
+                .collect();
+            // Buffers are owned by the Uring, and dropped after the ring.
+            unsafe { ring.submitter().register_buffers(&iovecs)? };
+            let slot = Slot {
+                state: SlotState::Free,
+                offset: 0,
+                len: 0,
+                written: 0,
+                writing: false,
+                hashed: false,
+            };
+            Ok(Uring {
+                ring,
+                buffers,
+                slots: vec![slot; depth],
+                block_size,
+                broken: false,
+                files: false,
+            })
+        }
+
+        pub fn depth(&self) -> usize {
+            self.slots.len()
+        }
+
+        pub fn block_size(&self) -> usize {
+            self.block_size
+        }
+
+        pub fn broken(&self) -> bool {
+            self.broken
+        }
+
+        fn push(&mut self, entry: io_uring::squeue::Entry) -> io::Result<()> {
+            if self.ring.submission().is_full() {
+                self.ring.submit()?;
+            }
+            // Buffers referenced by entry are registered and owned by self.
+            match unsafe { self.ring.submission().push(&entry) } {
+                Ok(()) => Ok(()),
+                Err(_) => Err(io::Error::other("io_uring submission queue full")),
+            }
+        }
+
+        // Push linked entries at once, so the chain is not split between
+        // submissions.
+        fn push_chain(&mut self, chain: &[squeue::Entry]) -> io::Result<()> {
+            let free = {
+                let submission = self.ring.submission();
+                submission.capacity() - submission.len()
+            };
+            if free < chain.len() {
+                self.ring.submit()?;
+            }
+            for entry in chain {
+                // Buffers referenced by entry are registered and owned by self.
+                if unsafe { self.ring.submission().push(entry) }.is_err() {
+                    return Err(io::Error::other("io_uring submission queue full"));
+                }
+            }
+            Ok(())
+        }
+
+        fn submit_read(&mut self, input: &File, slot: usize, offset: u64) -> io::Result<()> {
+            self.slots[slot].state = SlotState::Reading;
+            self.slots[slot].offset = offset;
+            self.slots[slot].len = 0;
+            self.slots[slot].written = 0;
+            self.slots[slot].writing = false;
+            self.slots[slot].hashed = false;
+            self.submit_read_rest(input, slot)
+        }
+
+        // Read the rest of the block, after len bytes of it were read.
+        fn submit_read_rest(&mut self, input: &File, slot: usize) -> io::Result<()> {
+            let s = self.slots[slot];
+            let buf = unsafe { self.buffers[slot].as_mut_ptr().add(s.len) };
+            let entry = opcode::ReadFixed::new(
+                types::Fd(input.as_raw_fd()),
+                buf,
+                (self.block_size - s.len) as u32,
+                slot as u16,
+            )
+            .offset(s.offset + s.len as u64)
+            .build()
+            .user_data(((slot as u64) << 1) | OP_READ);
+            self.push(entry)
+        }
+
+        fn submit_write(&mut self, output: &File, slot: usize) -> io::Result<()> {
+            let s = self.slots[slot];
+            let buf = unsafe { self.buffers[slot].as_ptr().add(s.written) };
+            let entry = opcode::WriteFixed::new(
+                types::Fd(output.as_raw_fd()),
+                buf,
+                (s.len - s.written) as u32,
+                slot as u16,
+            )
+            .offset(s.offset + s.written as u64)
+            .build()
+            .user_data(((slot as u64) << 1) | OP_WRITE);
+            self.slots[slot].writing = true;
+            self.push(entry)
+        }
+
+        pub fn transfer(
+            &mut self,
+            input: &File,
+            output: Option<&File>,
+            len: u64,
+            direct: bool,
+            progress: &mut dyn FnMut(usize),
+        ) -> io::Result<String> {
+            let block_size = self.block_size as u64;
+            let mut h1 = Sha256::new();
+            let mut next_read: u64 = 0;
+            let mut next_hash: u64 = 0;
+            let mut in_flight: usize = 0;
+            let mut error: Option<io::Error> = None;
+
+            loop {
+                // Keep every free buffer busy reading ahead.
+                if error.is_none() {
+                    for slot in 0..self.slots.len() {
+                        if next_read >= len {
+                            break;
+                        }
+                        if self.slots[slot].state != SlotState::Free {
+                            continue;
+                        }
+                        if let Err(e) = self.submit_read(input, slot, next_read) {
+                            error = Some(e);
+                            break;
+                        }
+                        next_read += block_size;
+                        in_flight += 1;
+                    }
+                }
+                if in_flight == 0 {
+                    break;
+                }
+                // One syscall submits the batch and waits for a completion.
+                if let Err(e) = self.ring.submit_and_wait(1) {
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    // Cannot safely reuse buffers the kernel may still own.
+                    self.broken = true;
+                    return Err(e);
+                }
+                let completed: Vec<(u64, i32)> = self
+                    .ring
+                    .completion()
+                    .map(|cqe| (cqe.user_data(), cqe.result()))
+                    .collect();
+                for (user_data, result) in completed {
+                    in_flight -= 1;
+                    let slot = (user_data >> 1) as usize;
+                    if result < 0 {
+                        if error.is_none() {
+                            error = Some(io::Error::from_raw_os_error(-result));
+                        }
+                        self.slots[slot].writing = false;
+                        self.slots[slot].state = SlotState::Free;
+                        continue;
+                    }
+                    let n = result as usize;
+                    if user_data & 1 == OP_READ {
+                        let offset = self.slots[slot].offset;
+                        let expected = (len - offset).min(block_size) as usize;
+                        self.slots[slot].len += n;
+                        let read = self.slots[slot].len;
+                        if read > expected && error.is_none() {
+                            error = Some(io::Error::other(format!(
+                                "file grew while reading, at offset {}",
+                                offset + expected as u64
+                            )));
+                        }
+                        if read < expected && error.is_none() {
+                            // Short reads are legal; read the rest.
+                            if n == 0 {
+                                error = Some(io::Error::new(
+                                    io::ErrorKind::UnexpectedEof,
+                                    format!("file truncated at offset {}", offset + read as u64),
+                                ));
+                            } else if let Err(e) = self.submit_read_rest(input, slot) {
+                                error = Some(e);
+                            } else {
+                                in_flight += 1;
+                                continue;
+                            }
+                        }
+                        self.slots[slot].state = SlotState::Filled;
+                        let n = read;
+                        if let Some(output) = output {
+                            if error.is_none() {
+                                if direct && !n.is_multiple_of(iotools::DIRECT_IO_ALIGNMENT) {
+                                    // Unaligned tail; continue without O_DIRECT.
+                                    if let Err(e) = iotools::clear_direct(output) {
+                                        error = Some(e);
+                                        continue;
+                                    }
+                                }
+                                if let Err(e) = self.submit_write(output, slot) {
+                                    error = Some(e);
+                                    continue;
+                                }
+                                in_flight += 1;
+                            }
+                        }
+                    } else {
+                        self.slots[slot].writing = false;
+                        self.slots[slot].written += n;
+                        let s = self.slots[slot];
+                        if n == 0 && error.is_none() {
+                            error = Some(io::Error::from(io::ErrorKind::WriteZero));
+                        }
+                        if let Some(output) = output {
+                            if s.written < s.len && error.is_none() {
+                                if let Err(e) = self.submit_write(output, slot) {
+                                    error = Some(e);
+                                    continue;
+                                }
+                                in_flight += 1;
+                            }
+                        }
+                    }
+                }
+
+                // Hash blocks in file order, as they become available.
+                loop {
+                    let ready = self.slots.iter().position(|s| {
+                        s.state == SlotState::Filled && !s.hashed && s.offset == next_hash
+                    });
+                    let slot = match ready {
+                        Some(slot) => slot,
+                        None => break,
+                    };
+                    let n = self.slots[slot].len;
+                    h1.update(&self.buffers[slot][0..n]);
+                    self.slots[slot].hashed = true;
+                    next_hash += n as u64;
+                    progress(n);
+                }
+
+                // Release buffers that are hashed and written.
+                for s in self.slots.iter_mut() {
+                    let done = match output {
+                        Some(_) => !s.writing && s.written >= s.len,
+                        None => true,
+                    };
+                    if s.state == SlotState::Filled && (s.hashed || error.is_some()) && done {
+                        s.state = SlotState::Free;
+                    }
+                }
+            }
+
+            for s in self.slots.iter_mut() {
+                s.state = SlotState::Free;
+            }
+            if let Some(e) = error {
+                return Err(e);
+            }
+            Ok(format!("{:x}", h1.finalize()))
+        }
+
+        // Submit the chain transferring a small file through slot: open
+        // the files as direct descriptors, read, write, sync and close them.
+        // A failing operation cancels the rest of the chain. Returns the
+        // number of operations.
+        fn submit_file(
+            &mut self,
+            slot: usize,
+            paths: &(CString, Option<CString>),
+            len: usize,
+            sync: bool,
+        ) -> io::Result<usize> {
+            let (input, output) = (2 * slot as u32, 2 * slot as u32 + 1);
+            let user_data = |op: u64| ((slot as u64) << 3) | op;
+            let link = squeue::Flags::IO_LINK;
+            let mut chain: Vec<squeue::Entry> = Vec::with_capacity(7);
+            chain.push(
+                opcode::OpenAt::new(types::Fd(libc::AT_FDCWD), paths.0.as_ptr())
+                    .flags(libc::O_RDONLY | libc::O_CLOEXEC)
+                    .file_index(Some(descriptor_slot(input)?))
+                    .build()
+                    .flags(link)
+                    .user_data(user_data(FILE_OPEN_INPUT)),
+            );
+            if len > 0 {
+                chain.push(
+                    opcode::ReadFixed::new(
+                        types::Fixed(input),
+                        self.buffers[slot].as_mut_ptr(),
+                        len as u32,
+                        slot as u16,
+                    )
+                    .offset(0)
+                    .build()
+                    .flags(link)
+                    .user_data(user_data(FILE_READ)),
+                );
+            }
+            let output_path = match &paths.1 {
+                Some(output_path) => output_path,
+                None => {
+                    chain.push(
+                        opcode::Close::new(types::Fixed(input))
+                            .build()
+                            .user_data(user_data(FILE_CLOSE)),
+                    );
+                    self.push_chain(&chain)?;
+                    return Ok(chain.len());
+                }
+            };
+            chain.push(
+                opcode::OpenAt::new(types::Fd(libc::AT_FDCWD), output_path.as_ptr())
+                    .flags(libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC)
+                    .mode(0o666)
+                    .file_index(Some(descriptor_slot(output)?))
+                    .build()
+                    .flags(link)
+                    .user_data(user_data(FILE_OPEN_OUTPUT)),
+            );
+            if len > 0 {
+                chain.push(
+                    opcode::WriteFixed::new(
+                        types::Fixed(output),
+                        self.buffers[slot].as_ptr(),
+                        len as u32,
+                        slot as u16,
+                    )
+                    .offset(0)
+                    .build()
+                    .flags(link)
+                    .user_data(user_data(FILE_WRITE)),
+                );
+            }
+            if sync {
+                chain.push(
+                    opcode::Fsync::new(types::Fixed(output))
+                        .build()
+                        .flags(link)
+                        .user_data(user_data(FILE_SYNC)),
+                );
+            }
+            chain.push(
+                opcode::Close::new(types::Fixed(input))
+                    .build()
+                    .flags(link)
+                    .user_data(user_data(FILE_CLOSE)),
+            );
+            chain.push(
+                opcode::Close::new(types::Fixed(output))
+                    .build()
+                    .user_data(user_data(FILE_CLOSE)),
+            );
+            self.push_chain(&chain)?;
+            Ok(chain.len())
+        }
+
+        // Close the descriptors of slot, after its chain failed.
+        fn submit_close(&mut self, slot: usize) -> io::Result<usize> {
+            let user_data = ((slot as u64) << 3) | FILE_CLOSE;
+            let chain = [
+                opcode::Close::new(types::Fixed(2 * slot as u32))
+                    .build()
+                    .user_data(user_data),
+                opcode::Close::new(types::Fixed(2 * slot as u32 + 1))
+                    .build()
+                    .user_data(user_data),
+            ];
+            self.push_chain(&chain)?;
+            Ok(chain.len())
+        }
+
+        pub fn transfer_files(
+            &mut self,
+            files: &[(&Path, Option<&Path>, u64)],
+            sync: bool,
+            progress: &mut dyn FnMut(usize),
+        ) -> io::Result<Vec<io::Result<String>>> {
+            if !self.files {
+                self.ring
+                    .submitter()
+                    .register_files_sparse(2 * self.slots.len() as u32)?;
+                self.files = true;
+            }
+            let mut paths: Vec<(CString, Option<CString>)> = Vec::with_capacity(files.len());
+            for (input, output, len) in files {
+                if *len > self.block_size as u64 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{}: larger than a block", input.display()),
+                    ));
+                }
+                paths.push((c_path(input)?, output.map(c_path).transpose()?));
+            }
+            let mut results: Vec<Option<io::Result<String>>> = Vec::with_capacity(files.len());
+            results.resize_with(files.len(), || None);
+            let mut slots: Vec<FileSlot> = Vec::with_capacity(self.slots.len());
+            slots.resize_with(self.slots.len(), FileSlot::default);
+            let mut next: usize = 0;
+            let mut done: usize = 0;
+            let mut in_flight: usize = 0;
+
+            while done < files.len() {
+                // Start the next file in every free slot.
+                for (slot, file_slot) in slots.iter_mut().enumerate() {
+                    if next >= files.len() {
+                        break;
+                    }
+                    if file_slot.pending != 0 {
+                        continue;
+                    }
+                    let len = files[next].2 as usize;
+                    let pending = match self.submit_file(slot, &paths[next], len, sync) {
+                        Ok(pending) => pending,
+                        Err(e) => {
+                            self.broken = in_flight > 0;
+                            return Err(e);
+                        }
+                    };
+                    *file_slot = FileSlot {
+                        file: next,
+                        pending,
+                        hash: (len == 0).then(|| format!("{:x}", Sha256::new().finalize())),
+                        ..FileSlot::default()
+                    };
+                    in_flight += pending;
+                    next += 1;
+                }
+                if let Err(e) = self.ring.submit_and_wait(1) {
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    // Cannot safely reuse buffers the kernel may still own.
+                    self.broken = true;
+                    return Err(e);
+                }
+                let completed: Vec<(u64, i32)> = self
+                    .ring
+                    .completion()
+                    .map(|cqe| (cqe.user_data(), cqe.result()))
+                    .collect();
+                for (user_data, result) in completed {
+                    in_flight -= 1;
+                    let slot = (user_data >> 3) as usize;
+                    let op = user_data & 7;
+                    let s = &mut slots[slot];
+                    s.pending -= 1;
+                    if result < 0 {
+                        // Failures while closing what a failed chain left open
+                        // do not matter.
+                        if s.error.is_none() {
+                            s.error = Some(io::Error::from_raw_os_error(-result));
+                        }
+                    } else if op == FILE_READ {
+                        let n = result as usize;
+                        if n as u64 != files[s.file].2 {
+                            // The rest of the chain is cancelled.
+                            s.error = Some(io::Error::new(
+                                io::ErrorKind::UnexpectedEof,
+                                format!("file changed while reading, at offset {}", n),
+                            ));
+                        } else {
+                            let mut h1 = Sha256::new();
+                            h1.update(&self.buffers[slot][0..n]);
+                            s.hash = Some(format!("{:x}", h1.finalize()));
+                            progress(n);
+                        }
+                    }
+                    if s.pending > 0 {
+                        continue;
+                    }
+                    if s.error.is_some() && !s.closing {
+                        s.closing = true;
+                        match self.submit_close(slot) {
+                            Ok(pending) => {
+                                slots[slot].pending = pending;
+                                in_flight += pending;
+                                continue;
+                            }
+                            Err(e) => {
+                                self.broken = in_flight > 0;
+                                return Err(e);
+                            }
+                        }
+                    }
+                    let s = &mut slots[slot];
+                    results[s.file] = Some(match (s.error.take(), s.hash.take()) {
+                        (Some(e), _) => Err(e),
+                        (None, Some(hash)) => Ok(hash),
+                        (None, None) => Err(io::Error::other("file not read")),
+                    });
+                    done += 1;
+                }
+            }
+            Ok(results
+                .into_iter()
+                .map(|r| r.unwrap_or_else(|| Err(io::Error::other("file not copied"))))
+                .collect())
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod unsupported {
+    use std::fs::File;
+    use std::io;
+    use std::path::Path;
+
+    pub struct Uring {}
+
+    impl Uring {
+        pub fn new(_depth: usize, _block_size: usize) -> io::Result<Uring> {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "io_uring is only available on Linux",
+            ))
+        }
+
+        pub fn depth(&self) -> usize {
+            0
+        }
+
+        pub fn block_size(&self) -> usize {
+            0
+        }
+
+        pub fn broken(&self) -> bool {
+            false
+        }
+
+        pub fn transfer(
+            &mut self,
+            _input: &File,
+            _output: Option<&File>,
+            _len: u64,
+            _direct: bool,
+            _progress: &mut dyn FnMut(usize),
+        ) -> io::Result<String> {
+            Err(io::Error::from(io::ErrorKind::Unsupported))
+        }
+
+        pub fn transfer_files(
+            &mut self,
+            _files: &[(&Path, Option<&Path>, u64)],
+            _sync: bool,
+            _progress: &mut dyn FnMut(usize),
+        ) -> io::Result<Vec<io::Result<String>>> {
+            Err(io::Error::from(io::ErrorKind::Unsupported))
+        }
+    }
+}